@@ -8,10 +8,14 @@ use coreaudio::audio_unit::{
 
 use crate::{
     AudioEngine,
+    AudioPipeline,
     AudioProcessor,
     EngineBuilder,
+    ErrorCallback,
     FRAME10MS,
     apple_platform_audio_processor::ApplePlatformAudioProcessor,
+    devices::DeviceSelection,
+    error,
     // empty_audio_processor::EmptyAudioProcessor,
 };
 
@@ -39,12 +43,22 @@ impl EngineBuilder for ApplePlatformAudioEngine {
     /// This function is **non-reentrant**. The caller must ensure that
     /// no two threads enter this function simultaneously.
     /// TODO: Rewrite this function.
-    fn build(
+    fn build_with_devices(
         encoder_input: rtrb::Producer<f32>,
         decoder_output: rtrb::Consumer<f32>,
-        encode_thread: std::thread::JoinHandle<()>,
-        mixer_thread: Arc<std::thread::JoinHandle<()>>,
+        encode_thread: std::thread::Thread,
+        mixer_thread: std::thread::Thread,
+        // coreaudio has no stream error callback to forward
+        _error_callback: ErrorCallback,
+        devices: &DeviceSelection,
+        // the voice processing unit cancels the echo itself
+        _pipeline: AudioPipeline,
     ) -> anyhow::Result<Arc<Self>> {
+        // the voice processing unit always follows the system devices
+        if *devices != DeviceSelection::default() {
+            return Err(error::Error::DeviceSelectionUnsupported.into());
+        }
+
         // config
         let mut vpio_unit = AudioUnit::new(IOType::VoiceProcessingIO)?;
         vpio_unit.uninitialize()?;
@@ -191,8 +205,8 @@ fn audiop(
     decoder_output: rtrb::Consumer<f32>,
     mut mic_cons: rtrb::Consumer<f32>,
     mut speaker_prod: rtrb::Producer<f32>,
-    encode_thread: std::thread::JoinHandle<()>,
-    mixer_thread: Arc<std::thread::JoinHandle<()>>,
+    encode_thread: std::thread::Thread,
+    mixer_thread: std::thread::Thread,
 ) -> anyhow::Result<()> {
     let mut ap = ApplePlatformAudioProcessor::build()?;
     let mut ap_ref_input = decoder_output;
@@ -204,8 +218,8 @@ fn audiop(
             &mut ap_mic_output,
             &mut speaker_prod,
         );
-        encode_thread.thread().unpark();
-        mixer_thread.thread().unpark();
+        encode_thread.unpark();
+        mixer_thread.unpark();
         std::thread::park();
     }
 }
@@ -1,19 +1,66 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        Arc, Mutex, PoisonError, Weak,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    time::Duration,
+};
+
+#[cfg(feature = "hachimi")]
+use libhachimi::audio_processing::CustomAudioProcessor;
 
-// use libhachimi::audio_processing::AudioProcessor;
+#[cfg(feature = "webrtc")]
+use crate::cross_platform_audio_processor::CrossPlatformAudioProcessor;
 use crate::{
-    AudioEngine, AudioProcessor, EngineBuilder, FRAME10MS, SAMPLE_RATE,
-    cross_platform_audio_processor::CrossPlatformAudioProcessor, error,
+    AudioEngine, AudioPipeline, AudioProcessor, EngineBuilder, ErrorCallback, FRAME10MS,
+    SAMPLE_RATE,
+    devices::{self, DeviceSelection},
+    error,
+    resampler::Resampler,
 };
 
 use cpal::{
-    self, SampleFormat, Stream, StreamConfig,
+    self, Device, FromSample, Host, SampleFormat, SizedSample, Stream, StreamConfig, StreamError,
+    SupportedStreamConfig, SupportedStreamConfigRange,
     traits::{DeviceTrait, HostTrait, StreamTrait},
 };
 
+/// Delay between two attempts to reopen a lost device.
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct DefaultAudioEngine {
-    input_stream: Stream,
-    output_stream: Stream,
+    streams: Arc<Mutex<Streams>>,
+}
+
+struct Streams {
+    input: Stream,
+    output: Stream,
+    playing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Input,
+    Output,
+}
+
+/// Sent by a stream's error callback when its device is gone. `generation`
+/// tells which stream of that direction reported it.
+struct DeviceLost {
+    direction: Direction,
+    generation: usize,
+}
+
+/// Everything the device thread needs to open a stream again. A lost
+/// default device is replaced by the new default one, a selected device is
+/// waited for until it comes back.
+struct StreamFactory {
+    host: Host,
+    devices: DeviceSelection,
+    audio_process: Arc<std::thread::JoinHandle<()>>,
+    lost_prod: mpsc::Sender<DeviceLost>,
+    error_callback: ErrorCallback,
 }
 
 impl EngineBuilder for DefaultAudioEngine {
@@ -21,162 +68,441 @@ impl EngineBuilder for DefaultAudioEngine {
     /// This function is **non-reentrant**. The caller must ensure that
     /// no two threads enter this function simultaneously.
     /// TODO: Rewrite this function.
-    fn build(
+    fn build_with_devices(
         encoder_input: rtrb::Producer<f32>,
         decoder_output: rtrb::Consumer<f32>,
-        encode_thread: std::thread::JoinHandle<()>,
-        mixer_thread: Arc<std::thread::JoinHandle<()>>,
+        encode_thread: std::thread::Thread,
+        mixer_thread: std::thread::Thread,
+        error_callback: ErrorCallback,
+        devices: &DeviceSelection,
+        pipeline: AudioPipeline,
     ) -> anyhow::Result<Arc<Self>> {
-        // config
-
-        let host = cpal::default_host();
-
-        let input_device = host
-            .default_input_device()
-            .ok_or(error::Error::InputDeviceInitError)?;
-
-        let mut supported_input_configs = input_device.supported_input_configs()?;
-        let input_config = supported_input_configs
-            .find(|config| {
-                config.sample_format() == SampleFormat::F32
-                    && config.min_sample_rate() <= SAMPLE_RATE
-                    && config.max_sample_rate() >= SAMPLE_RATE
-                    && config.channels() == 1
-            })
-            .map(|config| config.with_sample_rate(SAMPLE_RATE))
-            .ok_or(error::Error::UnsupportedInputSampleFormat)?;
-
-        let input_config: StreamConfig = input_config.into();
-
-        let output_device = host
-            .default_output_device()
-            .ok_or(error::Error::OutputDeviceInitError)?;
-
-        let mut supported_output_configs = output_device.supported_output_configs()?;
-        let output_config = supported_output_configs
-            .find(|config| {
-                config.sample_format() == SampleFormat::F32
-                    && config.min_sample_rate() <= SAMPLE_RATE
-                    && config.max_sample_rate() >= SAMPLE_RATE
-                    && config.channels() <= 2
-            })
-            .map(|config| config.with_sample_rate(SAMPLE_RATE))
-            .ok_or(error::Error::UnsupportedOutputSampleFormat)?;
-
-        let output_config: StreamConfig = output_config.into();
-
-        let output_channels = output_config.channels as usize;
-
         // buffer init
-        let (mut mic_prod, mic_cons) = rtrb::RingBuffer::new(FRAME10MS * 4);
-        let (speaker_prod, mut speaker_cons) = rtrb::RingBuffer::new(FRAME10MS * 4);
+        let (mic_prod, mic_cons) = rtrb::RingBuffer::new(FRAME10MS * 4);
+        let (speaker_prod, speaker_cons) = rtrb::RingBuffer::new(FRAME10MS * 4);
+
+        // a reopened device comes with fresh ring buffers
+        let (mic_swap_prod, mic_swap_cons) = mpsc::channel();
+        let (speaker_swap_prod, speaker_swap_cons) = mpsc::channel();
 
         // start threads
 
+        // the pipeline runs as long as the process, like the device thread
+        let stopped = Arc::new(AtomicBool::new(false));
         let audio_process = std::thread::Builder::new()
             .name("Audio Pipeline Thread".to_owned())
-            .spawn(|| {
+            .spawn(move || {
                 if audiop(
                     encoder_input,
                     decoder_output,
                     mic_cons,
                     speaker_prod,
+                    mic_swap_cons,
+                    speaker_swap_cons,
                     encode_thread,
                     mixer_thread,
+                    pipeline,
+                    &stopped,
                 )
                 .is_err()
                 {
                     // cancellation
                 }
             })?;
-        let audio_process = Arc::new(audio_process);
-        let audio_process_0 = audio_process.clone();
-        let audio_process_1 = audio_process.clone();
+
+        let (lost_prod, lost_cons) = mpsc::channel();
+        let factory = StreamFactory {
+            host: cpal::default_host(),
+            devices: devices.clone(),
+            audio_process: Arc::new(audio_process),
+            lost_prod,
+            error_callback,
+        };
+
+        let input = factory.build_input_stream(mic_prod, 0)?;
+        let output = factory.build_output_stream(speaker_cons, 0)?;
+        input.play()?;
+        output.play()?;
+        println!("Audio system running.");
+
+        let streams = Arc::new(Mutex::new(Streams {
+            input,
+            output,
+            playing: true,
+        }));
+
+        let weak_streams = Arc::downgrade(&streams);
+        std::thread::Builder::new()
+            .name("Audio Device Thread".to_owned())
+            .spawn(move || {
+                supervise(
+                    factory,
+                    weak_streams,
+                    lost_cons,
+                    mic_swap_prod,
+                    speaker_swap_prod,
+                )
+            })?;
+
+        Ok(Arc::new(DefaultAudioEngine { streams }))
+    }
+}
+
+impl AudioEngine for DefaultAudioEngine {
+    fn play(&mut self) -> anyhow::Result<()> {
+        let mut streams = self.streams.lock().unwrap_or_else(PoisonError::into_inner);
+        streams.input.play()?;
+        streams.output.play()?;
+        streams.playing = true;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> anyhow::Result<()> {
+        let mut streams = self.streams.lock().unwrap_or_else(PoisonError::into_inner);
+        streams.input.pause()?;
+        streams.output.pause()?;
+        streams.playing = false;
+        Ok(())
+    }
+}
+
+impl StreamFactory {
+    fn build_input_stream(
+        &self,
+        mic_prod: rtrb::Producer<f32>,
+        generation: usize,
+    ) -> anyhow::Result<Stream> {
+        let input_device = match &self.devices.input {
+            Some(id) => {
+                devices::find_device(id).ok_or(error::Error::DeviceNotFound(id.to_string()))?
+            }
+            None => self
+                .host
+                .default_input_device()
+                .ok_or(error::Error::InputDeviceInitError)?,
+        };
+        let input_config = best_config(input_device.supported_input_configs()?)
+            .ok_or(error::Error::UnsupportedInputSampleFormat)?;
+
+        let sample_format = input_config.sample_format();
+        let input_config: StreamConfig = input_config.into();
+        match sample_format {
+            SampleFormat::F32 => self.build_input_stream_as::<f32>(
+                &input_device,
+                &input_config,
+                mic_prod,
+                generation,
+            ),
+            SampleFormat::I16 => self.build_input_stream_as::<i16>(
+                &input_device,
+                &input_config,
+                mic_prod,
+                generation,
+            ),
+            SampleFormat::U16 => self.build_input_stream_as::<u16>(
+                &input_device,
+                &input_config,
+                mic_prod,
+                generation,
+            ),
+            _ => Err(error::Error::UnsupportedInputSampleFormat.into()),
+        }
+    }
+
+    /// Downmixes the device's frames and resamples them to `SAMPLE_RATE`.
+    fn build_input_stream_as<T>(
+        &self,
+        input_device: &Device,
+        input_config: &StreamConfig,
+        mut mic_prod: rtrb::Producer<f32>,
+        generation: usize,
+    ) -> anyhow::Result<Stream>
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+    {
+        let input_channels = input_config.channels as usize;
+        let mut resampler = Resampler::new(input_config.sample_rate, SAMPLE_RATE);
+
+        let audio_process_0 = self.audio_process.clone();
+        let lost_prod = self.lost_prod.clone();
+        let error_callback = self.error_callback.clone();
 
         let input_stream = input_device.build_input_stream(
-            &input_config,
-            move |data: &[f32], _| {
-                match mic_prod.write_chunk(data.len()) {
-                    Ok(mut chunk) => {
-                        let (w, _) = chunk.as_mut_slices();
-                        w.copy_from_slice(data);
-                        chunk.commit_all();
-                    }
-                    Err(_) => {
-                        audio_process_0.thread().unpark();
+            input_config,
+            move |data: &[T], _| {
+                for frame in data.chunks_exact(input_channels) {
+                    let sum: f32 = frame.iter().map(|sample| sample.to_sample::<f32>()).sum();
+                    resampler.push(sum / input_channels as f32);
+                    while let Some(sample) = resampler.pop() {
+                        // a full buffer drops the sample, the pipeline thread is woken below
+                        let _ = mic_prod.push(sample);
                     }
                 }
                 audio_process_0.thread().unpark();
             },
-            |err| panic!("input error: {:?}", err),
+            move |err| {
+                on_stream_error(
+                    err,
+                    Direction::Input,
+                    generation,
+                    &lost_prod,
+                    &error_callback,
+                )
+            },
             None,
         )?;
+        Ok(input_stream)
+    }
+
+    fn build_output_stream(
+        &self,
+        speaker_cons: rtrb::Consumer<f32>,
+        generation: usize,
+    ) -> anyhow::Result<Stream> {
+        let output_device = match &self.devices.output {
+            Some(id) => {
+                devices::find_device(id).ok_or(error::Error::DeviceNotFound(id.to_string()))?
+            }
+            None => self
+                .host
+                .default_output_device()
+                .ok_or(error::Error::OutputDeviceInitError)?,
+        };
+        let output_config = best_config(output_device.supported_output_configs()?)
+            .ok_or(error::Error::UnsupportedOutputSampleFormat)?;
+
+        let sample_format = output_config.sample_format();
+        let output_config: StreamConfig = output_config.into();
+        match sample_format {
+            SampleFormat::F32 => self.build_output_stream_as::<f32>(
+                &output_device,
+                &output_config,
+                speaker_cons,
+                generation,
+            ),
+            SampleFormat::I16 => self.build_output_stream_as::<i16>(
+                &output_device,
+                &output_config,
+                speaker_cons,
+                generation,
+            ),
+            SampleFormat::U16 => self.build_output_stream_as::<u16>(
+                &output_device,
+                &output_config,
+                speaker_cons,
+                generation,
+            ),
+            _ => Err(error::Error::UnsupportedOutputSampleFormat.into()),
+        }
+    }
+
+    /// Resamples the mono pipeline output to the device rate and copies it
+    /// to every channel.
+    fn build_output_stream_as<T>(
+        &self,
+        output_device: &Device,
+        output_config: &StreamConfig,
+        mut speaker_cons: rtrb::Consumer<f32>,
+        generation: usize,
+    ) -> anyhow::Result<Stream>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let output_channels = output_config.channels as usize;
+        let mut resampler = Resampler::new(SAMPLE_RATE, output_config.sample_rate);
+
+        let audio_process_1 = self.audio_process.clone();
+        let lost_prod = self.lost_prod.clone();
+        let error_callback = self.error_callback.clone();
 
         let output_stream = output_device.build_output_stream(
-            &output_config,
-            move |output: &mut [f32], _| {
+            output_config,
+            move |output: &mut [T], _| {
                 audio_process_1.thread().unpark();
                 for frame in output.chunks_exact_mut(output_channels) {
-                    if let Ok(sample) = speaker_cons.pop() {
-                        for channel_sample in frame.iter_mut() {
-                            *channel_sample = sample;
-                        }
-                    } else {
-                        for channel_sample in frame.iter_mut() {
-                            *channel_sample = 0.0;
+                    let sample = loop {
+                        if let Some(sample) = resampler.pop() {
+                            break sample;
                         }
+                        resampler.push(speaker_cons.pop().unwrap_or(0.0));
+                    };
+                    let sample = T::from_sample(sample);
+                    for channel_sample in frame.iter_mut() {
+                        *channel_sample = sample;
                     }
                 }
             },
-            |err| panic!("output error: {:?}", err),
+            move |err| {
+                on_stream_error(
+                    err,
+                    Direction::Output,
+                    generation,
+                    &lost_prod,
+                    &error_callback,
+                )
+            },
             None,
         )?;
+        Ok(output_stream)
+    }
+}
 
-        input_stream.play()?;
-        output_stream.play()?;
-        println!("Audio system running.");
+/// Picks the config that needs the least conversion: `SAMPLE_RATE` or the
+/// closest rate first, then float over integer samples, then fewer channels.
+fn best_config(
+    configs: impl Iterator<Item = SupportedStreamConfigRange>,
+) -> Option<SupportedStreamConfig> {
+    configs
+        .filter_map(|config| Some((format_rank(config.sample_format())?, config)))
+        .min_by_key(|(rank, config)| {
+            (
+                closest_sample_rate(config).abs_diff(SAMPLE_RATE),
+                *rank,
+                config.channels(),
+            )
+        })
+        .map(|(_, config)| {
+            let sample_rate = closest_sample_rate(&config);
+            config.with_sample_rate(sample_rate)
+        })
+}
 
-        Ok(Arc::new(DefaultAudioEngine {
-            input_stream,
-            output_stream,
-        }))
+fn closest_sample_rate(config: &SupportedStreamConfigRange) -> u32 {
+    SAMPLE_RATE.clamp(config.min_sample_rate(), config.max_sample_rate())
+}
+
+fn format_rank(sample_format: SampleFormat) -> Option<u8> {
+    match sample_format {
+        SampleFormat::F32 => Some(0),
+        SampleFormat::I16 => Some(1),
+        SampleFormat::U16 => Some(2),
+        _ => None,
     }
 }
 
-impl AudioEngine for DefaultAudioEngine {
-    fn play(&mut self) -> anyhow::Result<()> {
-        self.input_stream.play()?;
-        self.output_stream.play()?;
-        Ok(())
+fn on_stream_error(
+    err: StreamError,
+    direction: Direction,
+    generation: usize,
+    lost_prod: &mpsc::Sender<DeviceLost>,
+    error_callback: &ErrorCallback,
+) {
+    match err {
+        // a glitch, the stream goes on
+        StreamError::BufferUnderrun => return,
+        StreamError::DeviceNotAvailable | StreamError::StreamInvalidated => {
+            let _ = lost_prod.send(DeviceLost {
+                direction,
+                generation,
+            });
+        }
+        StreamError::BackendSpecific { .. } => {}
     }
+    error_callback(match direction {
+        Direction::Input => error::Error::InputStreamError(err.to_string()),
+        Direction::Output => error::Error::OutputStreamError(err.to_string()),
+    });
+}
 
-    fn pause(&mut self) -> anyhow::Result<()> {
-        self.input_stream.pause()?;
-        self.output_stream.pause()?;
-        Ok(())
+/// Reopens lost streams on the current default device, retrying until one is
+/// there, for as long as the engine lives. The encoder and mixer threads are
+/// left running meanwhile.
+fn supervise(
+    factory: StreamFactory,
+    streams: Weak<Mutex<Streams>>,
+    lost_cons: mpsc::Receiver<DeviceLost>,
+    mic_swap_prod: mpsc::Sender<rtrb::Consumer<f32>>,
+    speaker_swap_prod: mpsc::Sender<rtrb::Producer<f32>>,
+) {
+    let mut input_generation = 0;
+    let mut output_generation = 0;
+    let mut input_lost = false;
+    let mut output_lost = false;
+
+    loop {
+        match lost_cons.recv_timeout(REOPEN_INTERVAL) {
+            // a stream already replaced may still report errors
+            Ok(lost) if lost.direction == Direction::Input => {
+                input_lost |= lost.generation == input_generation;
+            }
+            Ok(lost) => {
+                output_lost |= lost.generation == output_generation;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+
+        let Some(streams) = streams.upgrade() else {
+            return;
+        };
+
+        if input_lost {
+            let (mic_prod, mic_cons) = rtrb::RingBuffer::new(FRAME10MS * 4);
+            if let Ok(input) = factory.build_input_stream(mic_prod, input_generation + 1) {
+                let mut streams = streams.lock().unwrap_or_else(PoisonError::into_inner);
+                if !streams.playing || input.play().is_ok() {
+                    let _ = mic_swap_prod.send(mic_cons);
+                    streams.input = input;
+                    input_generation += 1;
+                    input_lost = false;
+                }
+            }
+        }
+
+        if output_lost {
+            let (speaker_prod, speaker_cons) = rtrb::RingBuffer::new(FRAME10MS * 4);
+            if let Ok(output) = factory.build_output_stream(speaker_cons, output_generation + 1) {
+                let mut streams = streams.lock().unwrap_or_else(PoisonError::into_inner);
+                if !streams.playing || output.play().is_ok() {
+                    let _ = speaker_swap_prod.send(speaker_prod);
+                    streams.output = output;
+                    output_generation += 1;
+                    output_lost = false;
+                }
+            }
+        }
     }
 }
 
-fn audiop(
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn audiop(
     encoder_input: rtrb::Producer<f32>,
     decoder_output: rtrb::Consumer<f32>,
     mut mic_cons: rtrb::Consumer<f32>,
     mut speaker_prod: rtrb::Producer<f32>,
-    encode_thread: std::thread::JoinHandle<()>,
-    mixer_thread: Arc<std::thread::JoinHandle<()>>,
+    mic_swap_cons: mpsc::Receiver<rtrb::Consumer<f32>>,
+    speaker_swap_cons: mpsc::Receiver<rtrb::Producer<f32>>,
+    encode_thread: std::thread::Thread,
+    mixer_thread: std::thread::Thread,
+    pipeline: AudioPipeline,
+    stopped: &AtomicBool,
 ) -> anyhow::Result<()> {
-    let mut ap = CrossPlatformAudioProcessor::build()?;
+    let mut ap: Box<dyn AudioProcessor> = match pipeline {
+        #[cfg(feature = "webrtc")]
+        AudioPipeline::WebRtc => Box::new(CrossPlatformAudioProcessor::build()?),
+        #[cfg(feature = "hachimi")]
+        AudioPipeline::Hachimi(config) => Box::new(CustomAudioProcessor::with_echo_config(config)),
+    };
     let mut ap_ref_input = decoder_output;
     let mut ap_mic_output = encoder_input;
-    loop {
+    // checked after every wake up, the owner unparks the thread once it is set
+    while !stopped.load(Ordering::Acquire) {
+        if let Ok(cons) = mic_swap_cons.try_recv() {
+            mic_cons = cons;
+        }
+        if let Ok(prod) = speaker_swap_cons.try_recv() {
+            speaker_prod = prod;
+        }
         ap.process(
             &mut mic_cons,
             &mut ap_ref_input,
             &mut ap_mic_output,
             &mut speaker_prod,
         );
-        encode_thread.thread().unpark();
-        mixer_thread.thread().unpark();
+        encode_thread.unpark();
+        mixer_thread.unpark();
         std::thread::park();
     }
+    Ok(())
 }
@@ -0,0 +1,78 @@
+pub use cpal::{DeviceId, HostId, SupportedStreamConfigRange};
+
+use cpal::{
+    Device,
+    traits::{DeviceTrait, HostTrait},
+};
+
+/// Devices an engine opens instead of the system defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceSelection {
+    pub input: Option<DeviceId>,
+    pub output: Option<DeviceId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HostInfo {
+    pub id: HostId,
+    pub devices: Vec<DeviceInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub name: String,
+    pub is_default_input: bool,
+    pub is_default_output: bool,
+    pub input_configs: Vec<SupportedStreamConfigRange>,
+    pub output_configs: Vec<SupportedStreamConfigRange>,
+}
+
+/// Lists the devices of every audio host available on this platform.
+/// Hosts and devices that fail to answer are left out.
+pub fn list_hosts() -> Vec<HostInfo> {
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|host_id| {
+            let host = cpal::host_from_id(host_id).ok()?;
+            let default_input = host.default_input_device().and_then(|d| d.id().ok());
+            let default_output = host.default_output_device().and_then(|d| d.id().ok());
+
+            let devices = host
+                .devices()
+                .ok()?
+                .filter_map(|device| {
+                    let id = device.id().ok()?;
+                    let name = device
+                        .description()
+                        .map(|description| description.name().to_owned())
+                        .unwrap_or_else(|_| id.1.clone());
+                    Some(DeviceInfo {
+                        is_default_input: default_input.as_ref() == Some(&id),
+                        is_default_output: default_output.as_ref() == Some(&id),
+                        input_configs: device
+                            .supported_input_configs()
+                            .map(Iterator::collect)
+                            .unwrap_or_default(),
+                        output_configs: device
+                            .supported_output_configs()
+                            .map(Iterator::collect)
+                            .unwrap_or_default(),
+                        id,
+                        name,
+                    })
+                })
+                .collect();
+
+            Some(HostInfo {
+                id: host_id,
+                devices,
+            })
+        })
+        .collect()
+}
+
+/// Opens a device by the id `list_hosts` reported for it.
+pub fn find_device(id: &DeviceId) -> Option<Device> {
+    cpal::host_from_id(id.0).ok()?.device_by_id(id)
+}
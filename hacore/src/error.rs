@@ -10,4 +10,12 @@ pub enum Error {
     UnsupportedInputSampleFormat,
     #[error("unsupported output sample format")]
     UnsupportedOutputSampleFormat,
+    #[error("audio device {0} not found")]
+    DeviceNotFound(String),
+    #[error("this engine only runs on the default devices")]
+    DeviceSelectionUnsupported,
+    #[error("input stream error: {0}")]
+    InputStreamError(String),
+    #[error("output stream error: {0}")]
+    OutputStreamError(String),
 }
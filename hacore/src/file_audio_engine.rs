@@ -0,0 +1,331 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
+};
+
+use cpal::Sample;
+use hound::{WavReader, WavSpec, WavWriter};
+
+use crate::{
+    AudioEngine, AudioPipeline, FRAME10MS, SAMPLE_RATE, default_audio_engine::audiop,
+    resampler::Resampler,
+};
+
+/// How long a faster than real time clock waits for the pipeline thread to
+/// take a period before it gives up on it.
+const PIPELINE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often it looks again meanwhile.
+const PIPELINE_POLL: Duration = Duration::from_micros(200);
+
+/// Where a `FileAudioEngine` takes its microphone samples from.
+#[derive(Debug, Clone)]
+pub enum FileSource {
+    /// Any PCM or float WAV file, downmixed and resampled to 48 kHz mono.
+    /// Silence follows once the file has been played.
+    Wav(PathBuf),
+    Sine {
+        frequency: f32,
+        amplitude: f32,
+    },
+    Silence,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileEngineConfig {
+    pub source: FileSource,
+    /// 48 kHz mono 16-bit WAV file the speaker output is written to.
+    pub sink: Option<PathBuf>,
+    /// Tick every 10 ms like a sound card does. Otherwise each tick waits
+    /// for the pipeline thread only, which is faster than real time.
+    pub realtime: bool,
+}
+
+impl Default for FileEngineConfig {
+    fn default() -> Self {
+        Self {
+            source: FileSource::Silence,
+            sink: None,
+            realtime: true,
+        }
+    }
+}
+
+/// Audio engine without sound hardware: reads the microphone from a file or
+/// a generator and writes the speaker to a file, on a simulated clock.
+pub struct FileAudioEngine {
+    playing: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    clock_thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for FileAudioEngine {
+    fn drop(&mut self) {
+        // the clock thread stops the pipeline thread and finalizes the
+        // output file on its way out
+        self.stopped.store(true, Ordering::Release);
+        if let Some(clock_thread) = self.clock_thread.take() {
+            let _ = clock_thread.join();
+        }
+    }
+}
+
+type Samples = Box<dyn Iterator<Item = f32> + Send>;
+
+impl FileAudioEngine {
+    pub fn build(
+        encoder_input: rtrb::Producer<f32>,
+        decoder_output: rtrb::Consumer<f32>,
+        encode_thread: std::thread::Thread,
+        mixer_thread: std::thread::Thread,
+        config: &FileEngineConfig,
+        pipeline: AudioPipeline,
+    ) -> anyhow::Result<Arc<Self>> {
+        let source = open_source(&config.source)?;
+        let sink = match &config.sink {
+            Some(path) => Some(WavWriter::create(
+                path,
+                WavSpec {
+                    channels: 1,
+                    sample_rate: SAMPLE_RATE,
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                },
+            )?),
+            None => None,
+        };
+
+        // buffer init
+        let (mic_prod, mic_cons) = rtrb::RingBuffer::new(FRAME10MS * 4);
+        let (speaker_prod, speaker_cons) = rtrb::RingBuffer::new(FRAME10MS * 4);
+
+        // files do not go away, nothing is ever swapped
+        let (_, mic_swap_cons) = mpsc::channel();
+        let (_, speaker_swap_cons) = mpsc::channel();
+
+        // start threads
+
+        let playing = Arc::new(AtomicBool::new(true));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let pipeline_stopped = stopped.clone();
+        let audio_process = std::thread::Builder::new()
+            .name("Audio Pipeline Thread".to_owned())
+            .spawn(move || {
+                if audiop(
+                    encoder_input,
+                    decoder_output,
+                    mic_cons,
+                    speaker_prod,
+                    mic_swap_cons,
+                    speaker_swap_cons,
+                    encode_thread,
+                    mixer_thread,
+                    pipeline,
+                    &pipeline_stopped,
+                )
+                .is_err()
+                {
+                    // cancellation
+                }
+            })?;
+
+        let clock = FileClock {
+            source,
+            sink,
+            mic_prod,
+            speaker_cons,
+            audio_process,
+            playing: playing.clone(),
+            stopped: stopped.clone(),
+            realtime: config.realtime,
+        };
+        let clock_thread = std::thread::Builder::new()
+            .name("Audio File Clock Thread".to_owned())
+            .spawn(move || {
+                if let Err(err) = clock.run() {
+                    eprintln!("Audio file clock stopped: {err}");
+                }
+            })?;
+
+        Ok(Arc::new(FileAudioEngine {
+            playing,
+            stopped,
+            clock_thread: Some(clock_thread),
+        }))
+    }
+}
+
+impl AudioEngine for FileAudioEngine {
+    fn play(&mut self) -> anyhow::Result<()> {
+        self.playing.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn pause(&mut self) -> anyhow::Result<()> {
+        self.playing.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Plays the part of the sound card callbacks, one 10 ms period per tick.
+struct FileClock {
+    source: Samples,
+    sink: Option<WavWriter<BufWriter<File>>>,
+    mic_prod: rtrb::Producer<f32>,
+    speaker_cons: rtrb::Consumer<f32>,
+    audio_process: std::thread::JoinHandle<()>,
+    playing: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    realtime: bool,
+}
+
+impl FileClock {
+    fn run(mut self) -> anyhow::Result<()> {
+        let result = self.clock();
+
+        // also when the clock failed, so that the pipeline thread goes away
+        self.stopped.store(true, Ordering::Release);
+        self.audio_process.thread().unpark();
+        // a stalled one would hang the engine's drop, it is left behind
+        if result.is_ok() {
+            let _ = self.audio_process.join();
+        }
+
+        if let Some(sink) = self.sink {
+            sink.finalize()?;
+        }
+        result
+    }
+
+    fn clock(&mut self) -> anyhow::Result<()> {
+        let tick = Duration::from_millis(10);
+        let mut deadline = Instant::now();
+
+        while !self.stopped.load(Ordering::Acquire) {
+            let playing = self.playing.load(Ordering::Relaxed);
+            if playing {
+                self.tick()?;
+            }
+
+            if self.realtime || !playing {
+                deadline += tick;
+                let now = Instant::now();
+                if deadline > now {
+                    std::thread::sleep(deadline - now);
+                } else {
+                    deadline = now;
+                }
+            } else {
+                self.wait_for_pipeline()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets the pipeline thread catch up before the next period.
+    fn wait_for_pipeline(&self) -> anyhow::Result<()> {
+        let started = Instant::now();
+        while self.mic_prod.slots() < self.mic_prod.buffer().capacity()
+            && !self.stopped.load(Ordering::Acquire)
+        {
+            if started.elapsed() > PIPELINE_TIMEOUT {
+                anyhow::bail!("audio pipeline took no samples for {PIPELINE_TIMEOUT:?}");
+            }
+            self.audio_process.thread().unpark();
+            std::thread::sleep(PIPELINE_POLL);
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> anyhow::Result<()> {
+        if let Ok(mut chunk) = self.mic_prod.write_chunk(FRAME10MS) {
+            let (first, second) = chunk.as_mut_slices();
+            for sample in first.iter_mut().chain(second.iter_mut()) {
+                *sample = self.source.next().unwrap_or(0.0);
+            }
+            chunk.commit_all();
+        }
+        self.audio_process.thread().unpark();
+
+        for _ in 0..FRAME10MS {
+            // an empty buffer plays silence, as on a sound card
+            let sample = self.speaker_cons.pop().unwrap_or(0.0);
+            if let Some(sink) = self.sink.as_mut() {
+                sink.write_sample(sample.to_sample::<i16>())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open_source(source: &FileSource) -> anyhow::Result<Samples> {
+    Ok(match *source {
+        FileSource::Wav(ref path) => {
+            let reader = WavReader::open(path)?;
+            let spec = reader.spec();
+            let samples: Samples = match spec.sample_format {
+                hound::SampleFormat::Float => {
+                    Box::new(reader.into_samples::<f32>().map_while(Result::ok))
+                }
+                hound::SampleFormat::Int => {
+                    let scale = 1.0 / (1u32 << (spec.bits_per_sample - 1)) as f32;
+                    Box::new(
+                        reader
+                            .into_samples::<i32>()
+                            .map_while(Result::ok)
+                            .map(move |sample| sample as f32 * scale),
+                    )
+                }
+            };
+            let wav = WavSamples {
+                samples,
+                channels: spec.channels as usize,
+                resampler: Resampler::new(spec.sample_rate, SAMPLE_RATE),
+            };
+            Box::new(wav.chain(std::iter::repeat(0.0)))
+        }
+        FileSource::Sine {
+            frequency,
+            amplitude,
+        } => {
+            let step = std::f32::consts::TAU * frequency / SAMPLE_RATE as f32;
+            let mut phase = 0.0f32;
+            Box::new(std::iter::repeat_with(move || {
+                let sample = amplitude * phase.sin();
+                phase = (phase + step) % std::f32::consts::TAU;
+                sample
+            }))
+        }
+        FileSource::Silence => Box::new(std::iter::repeat(0.0)),
+    })
+}
+
+/// Frames of a WAV file downmixed and resampled to `SAMPLE_RATE` mono.
+struct WavSamples {
+    samples: Samples,
+    channels: usize,
+    resampler: Resampler,
+}
+
+impl Iterator for WavSamples {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        loop {
+            if let Some(sample) = self.resampler.pop() {
+                return Some(sample);
+            }
+            let mut sum = 0.0;
+            for _ in 0..self.channels {
+                sum += self.samples.next()?;
+            }
+            self.resampler.push(sum / self.channels as f32);
+        }
+    }
+}
@@ -1,28 +1,92 @@
 use std::sync::Arc;
 
+use crate::devices::DeviceSelection;
+
 // use bytes::Bytes;
 
 #[cfg(target_vendor = "apple")]
 pub mod apple_platform_audio_engine;
 #[cfg(target_vendor = "apple")]
 pub mod apple_platform_audio_processor;
+#[cfg(feature = "webrtc")]
 pub mod cross_platform_audio_processor;
 pub mod default_audio_engine;
+pub mod devices;
 pub mod empty_audio_processor;
 pub mod error;
+pub mod file_audio_engine;
+pub mod resampler;
+
+pub use libhachimi::AudioProcessor;
+#[cfg(feature = "hachimi")]
+pub use libhachimi::{
+    audio_processing::{EchoCanceller, EchoConfig},
+    residual_echo::SuppressionLevel,
+};
 
-// use libhachimi::audio_processing::AudioProcessor;
+#[cfg(not(any(feature = "webrtc", feature = "hachimi")))]
+compile_error!("at least one of the `webrtc` and `hachimi` features must be enabled");
 
 pub const SAMPLE_RATE: u32 = 48000;
 pub const FRAME10MS: usize = 480;
 pub const FRAME20MS: usize = 960;
 
+/// Called from the audio backend when a running device fails.
+pub type ErrorCallback = Arc<dyn Fn(error::Error) + Send + Sync>;
+
+/// Which `AudioProcessor` cleans up the microphone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioPipeline {
+    /// webrtc audio processing, built from the bundled C++.
+    #[cfg(feature = "webrtc")]
+    WebRtc,
+    /// libhachimi's pure-Rust pipeline, with the given echo removal.
+    #[cfg(feature = "hachimi")]
+    Hachimi(EchoConfig),
+}
+
+/// webrtc when it is built, libhachimi otherwise.
+impl Default for AudioPipeline {
+    #[cfg(feature = "webrtc")]
+    fn default() -> Self {
+        Self::WebRtc
+    }
+
+    #[cfg(not(feature = "webrtc"))]
+    fn default() -> Self {
+        Self::Hachimi(EchoConfig::default())
+    }
+}
+
 pub trait EngineBuilder {
     fn build(
         encoder_input: rtrb::Producer<f32>,
         decoder_output: rtrb::Consumer<f32>,
-        encode_thread: std::thread::JoinHandle<()>,
-        mixer_thread: Arc<std::thread::JoinHandle<()>>,
+        encode_thread: std::thread::Thread,
+        mixer_thread: std::thread::Thread,
+        error_callback: ErrorCallback,
+    ) -> anyhow::Result<Arc<Self>> {
+        Self::build_with_devices(
+            encoder_input,
+            decoder_output,
+            encode_thread,
+            mixer_thread,
+            error_callback,
+            &DeviceSelection::default(),
+            AudioPipeline::default(),
+        )
+    }
+
+    /// Same as `build`, on the selected devices instead of the system
+    /// defaults and through the selected pipeline.
+    fn build_with_devices(
+        encoder_input: rtrb::Producer<f32>,
+        decoder_output: rtrb::Consumer<f32>,
+        encode_thread: std::thread::Thread,
+        mixer_thread: std::thread::Thread,
+        error_callback: ErrorCallback,
+        devices: &DeviceSelection,
+        pipeline: AudioPipeline,
     ) -> anyhow::Result<Arc<Self>>;
 }
 
@@ -30,13 +94,3 @@ pub trait AudioEngine {
     fn play(&mut self) -> anyhow::Result<()>;
     fn pause(&mut self) -> anyhow::Result<()>;
 }
-
-pub trait AudioProcessor {
-    fn process(
-        &mut self,
-        mic_cons: &mut rtrb::Consumer<f32>,
-        ref_cons: &mut rtrb::Consumer<f32>,
-        mic_prod: &mut rtrb::Producer<f32>,
-        ref_prod: &mut rtrb::Producer<f32>,
-    );
-}
@@ -0,0 +1,168 @@
+/// Cutoff of the anti-aliasing filter, as a share of the output rate.
+const CUTOFF: f64 = 0.45;
+/// Q of the two sections of a 4th-order Butterworth low-pass.
+const BUTTERWORTH_Q: [f64; 2] = [0.541_196_1, 1.306_563];
+
+/// Streaming sample-rate converter for one channel, interpolating between
+/// input samples with a 4-point cubic Hermite curve.
+///
+/// When downsampling, the input is low-passed below the output's Nyquist
+/// frequency first, or what is above would fold back as aliasing.
+///
+/// Input and output sides are decoupled: `push` input samples as they come
+/// and `pop` output samples until it returns `None`.
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    /// Input samples per output sample.
+    step: f64,
+    /// Position of the next output sample between `history[1]` and `history[2]`.
+    position: f64,
+    history: [f32; 4],
+    anti_alias: Option<[LowPass; 2]>,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        let step = input_rate as f64 / output_rate as f64;
+        let anti_alias =
+            (step > 1.0).then(|| BUTTERWORTH_Q.map(|q| LowPass::new(CUTOFF / step, q)));
+        Self {
+            step,
+            // the curve starts on the silence the history is primed with,
+            // instead of being extrapolated before it
+            position: 1.0,
+            history: [0.0; 4],
+            anti_alias,
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        let sample = match &mut self.anti_alias {
+            Some(sections) => sections
+                .iter_mut()
+                .fold(sample, |sample, section| section.run(sample)),
+            None => sample,
+        };
+        self.history.rotate_left(1);
+        self.history[3] = sample;
+        self.position -= 1.0;
+    }
+
+    /// Returns the next output sample, or `None` when the next one needs
+    /// more input.
+    pub fn pop(&mut self) -> Option<f32> {
+        if self.position >= 1.0 {
+            return None;
+        }
+        let t = self.position as f32;
+        self.position += self.step;
+
+        let [x0, x1, x2, x3] = self.history;
+        let c1 = 0.5 * (x2 - x0);
+        let c2 = x0 - 2.5 * x1 + 2.0 * x2 - 0.5 * x3;
+        let c3 = 0.5 * (x3 - x0) + 1.5 * (x1 - x2);
+        Some(((c3 * t + c2) * t + c1) * t + x1)
+    }
+}
+
+/// Second-order low-pass section, transposed direct form II.
+#[derive(Debug, Clone, Copy)]
+struct LowPass {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl LowPass {
+    /// `cutoff`: as a share of the sample rate.
+    fn new(cutoff: f64, q: f64) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * cutoff;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: ((1.0 - cos) / 2.0 / a0) as f32,
+            b1: ((1.0 - cos) / a0) as f32,
+            b2: ((1.0 - cos) / 2.0 / a0) as f32,
+            a1: (-2.0 * cos / a0) as f32,
+            a2: ((1.0 - alpha) / a0) as f32,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn run(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resamples one second of `input`, which is at `input_rate`.
+    fn resample(input_rate: u32, output_rate: u32, input: impl Fn(usize) -> f32) -> Vec<f32> {
+        let mut resampler = Resampler::new(input_rate, output_rate);
+        let mut output = Vec::new();
+        for i in 0..input_rate as usize {
+            resampler.push(input(i));
+            output.extend(std::iter::from_fn(|| resampler.pop()));
+        }
+        output
+    }
+
+    fn tone(rate: u32, frequency: f32) -> impl Fn(usize) -> f32 {
+        move |i| (i as f32 * frequency * std::f32::consts::TAU / rate as f32).sin()
+    }
+
+    /// RMS of the second half, once the filter has settled.
+    fn settled_rms(output: &[f32]) -> f32 {
+        let tail = &output[output.len() / 2..];
+        (tail.iter().map(|x| x * x).sum::<f32>() / tail.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn keeps_a_constant_level() {
+        for (input_rate, output_rate) in [(44_100, 48_000), (48_000, 16_000)] {
+            let output = resample(input_rate, output_rate, |_| 0.5);
+            for &sample in &output[output.len() / 2..] {
+                assert!(
+                    (sample - 0.5).abs() < 1e-3,
+                    "{input_rate} to {output_rate}: {sample}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn outputs_one_second_per_second() {
+        for (input_rate, output_rate) in [(44_100, 48_000), (48_000, 16_000)] {
+            let output = resample(input_rate, output_rate, |_| 0.0);
+            assert!(
+                output.len().abs_diff(output_rate as usize) <= 2,
+                "{input_rate} to {output_rate}: {}",
+                output.len()
+            );
+        }
+    }
+
+    #[test]
+    fn removes_what_is_above_the_output_nyquist_frequency() {
+        let speech = settled_rms(&resample(48_000, 16_000, tone(48_000, 1_000.0)));
+        assert!(
+            (speech - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.05,
+            "{speech}"
+        );
+
+        // it would fold back to 2 kHz
+        let aliased = settled_rms(&resample(48_000, 16_000, tone(48_000, 14_000.0)));
+        assert!(aliased < speech * 0.1, "{aliased} of {speech}");
+    }
+}
@@ -8,6 +8,7 @@ pub struct AecGuard {
     trigger_threshold: usize,
     cooldown_remaining: usize,
     cooldown_limit_frame: usize,
+    protected: bool,
 }
 
 impl AecGuard {
@@ -20,40 +21,64 @@ impl AecGuard {
             assume_frame: 0,
             cooldown_remaining: 0,
             cooldown_limit_frame,
+            protected: false,
         }
     }
 
-    /// `return`: is_diverged
+    /// Whether the last frame was replaced by the microphone.
+    pub fn protected(&self) -> bool {
+        self.protected
+    }
+
+    /// `double_talk`: near-end speech makes the output loud on its own, so
+    /// those frames are not counted as divergence.
+    ///
+    /// `return`: is_diverged, after `trigger_threshold` suspicious frames in a row
     pub fn examine_and_protect<const FRAME_SIZE: usize>(
         &mut self,
         mic_frame: &[f32; FRAME_SIZE],
         output_frame: &mut [f32; FRAME_SIZE],
+        double_talk: bool,
     ) -> bool {
-        if self.assume_frame == self.trigger_threshold {
-            self.assume_frame = 0;
-            self.cooldown_remaining = self.cooldown_limit_frame;
-            self.limiter = self.init_limiter;
-            return true;
+        self.protected = false;
+        if self.cooldown_remaining > 0 {
+            self.protect(mic_frame, output_frame);
+            return false;
         }
 
-        if self.cooldown_remaining > 0 {
-            *output_frame = *mic_frame;
-            self.limiter.process(output_frame);
-            self.cooldown_remaining -= 1;
+        if !self.is_diverged(mic_frame, output_frame, double_talk) {
+            self.assume_frame = 0;
             return false;
         }
 
-        if self.is_diverged(mic_frame, output_frame) {
-            self.assume_frame += 1;
-            return true;
+        self.assume_frame += 1;
+        if self.assume_frame < self.trigger_threshold {
+            return false;
         }
-        false
+        self.assume_frame = 0;
+        self.cooldown_remaining = self.cooldown_limit_frame;
+        self.limiter = self.init_limiter;
+        self.protect(mic_frame, output_frame);
+        true
+    }
+
+    /// Sends the limited microphone while the filter starts over.
+    fn protect<const FRAME_SIZE: usize>(
+        &mut self,
+        mic_frame: &[f32; FRAME_SIZE],
+        output_frame: &mut [f32; FRAME_SIZE],
+    ) {
+        *output_frame = *mic_frame;
+        self.limiter.process(output_frame);
+        self.protected = true;
+        self.cooldown_remaining = self.cooldown_remaining.saturating_sub(1);
     }
 
     fn is_diverged<const FRAME_SIZE: usize>(
         &self,
         mic: &[f32; FRAME_SIZE],
         out: &[f32; FRAME_SIZE],
+        double_talk: bool,
     ) -> bool {
         let mut e_mic = 0.0f32;
         let mut e_out = 0.0f32;
@@ -66,6 +91,6 @@ impl AecGuard {
             e_out += o * o;
         }
 
-        (e_mic > 1e-6) && (e_out > (e_mic * 1.6))
+        !double_talk && (e_mic > 1e-6) && (e_out > (e_mic * 1.6))
     }
 }
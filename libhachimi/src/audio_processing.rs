@@ -1,61 +1,189 @@
-use alloc::boxed::Box;
 use biquad::*;
 use fdaf_aec::FdafAec;
 use nnnoiseless::DenoiseState;
-use ringbuf::{
-    HeapCons, HeapProd, LocalRb,
-    storage::Heap,
-    traits::{Consumer, Observer, Producer, Split},
-};
+use rtrb::{Consumer, Producer, RingBuffer};
 
 use crate::{
-    AudioProcessor, aec_guard::AecGuard, constant::*, limiter::SmoothLimiter, noise_gate::*,
+    AudioProcessor,
+    aec_guard::AecGuard,
+    constant::*,
+    delay_estimator::DelayEstimator,
+    double_talk::DoubleTalkDetector,
+    limiter::SmoothLimiter,
+    noise_gate::*,
+    residual_echo::{EchoSpectra, ResidualEchoSuppressor, SuppressionLevel},
+    try_impl_aec::PbfdafAec,
 };
 
-type BufProd = <LocalRb<Heap<f32>> as Split>::Prod;
-type BufCons = <LocalRb<Heap<f32>> as Split>::Cons;
+type PartitionedAec = PbfdafAec<AEC_FRAME_SIZE, AEC_FFT_SIZE, AEC_PARTITIONS, AEC_MAX_DELAY_BLOCKS>;
+
+/// Adaptive filter used by the echo canceller.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EchoCanceller {
+    /// Single block filter, covers one frame of echo path.
+    #[default]
+    Fdaf,
+    /// Partitioned block filter covering a longer echo tail, with the far end
+    /// aligned to the measured speaker to microphone delay.
+    Pbfdaf,
+}
+
+/// How the echo is removed from the microphone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EchoConfig {
+    pub canceller: EchoCanceller,
+    /// Residual echo suppression after the adaptive filter.
+    pub suppression: SuppressionLevel,
+}
+
+/// Echo canceller state, and what it is reset to when it diverges.
+pub enum EchoFilter {
+    Fdaf {
+        state: FdafAec<AEC_FFT_SIZE>,
+        init: FdafAec<AEC_FFT_SIZE>,
+        /// What the filter knew before a frame it must not learn from.
+        snapshot: FdafAec<AEC_FFT_SIZE>,
+    },
+    Pbfdaf {
+        state: Box<PartitionedAec>,
+        estimator: DelayEstimator,
+    },
+}
+
+impl EchoFilter {
+    /// Longest `echo_window` may return.
+    pub const MAX_ECHO_WINDOW: usize = AEC_MAX_DELAY_BLOCKS + AEC_PARTITIONS;
+
+    pub fn new(canceller: EchoCanceller) -> Self {
+        match canceller {
+            EchoCanceller::Fdaf => {
+                let state = FdafAec::<AEC_FFT_SIZE>::new(STEP_SIZE, 0.9, 10e-2, 10e-6);
+                Self::Fdaf {
+                    init: state.clone(),
+                    snapshot: state.clone(),
+                    state,
+                }
+            }
+            EchoCanceller::Pbfdaf => Self::Pbfdaf {
+                state: Box::new(PartitionedAec::new(STEP_SIZE, 0)),
+                estimator: DelayEstimator::new(AEC_FRAME_SIZE * AEC_MAX_DELAY_BLOCKS),
+            },
+        }
+    }
+
+    /// `adapt`: false during double talk, the echo is then cancelled with
+    /// what the filter has learned so far.
+    /// `spectra`: filled with the frame's error and echo estimate if given.
+    pub fn process(
+        &mut self,
+        out: &mut [f32; AEC_FRAME_SIZE],
+        far: &[f32; AEC_FRAME_SIZE],
+        mic: &[f32; AEC_FRAME_SIZE],
+        adapt: bool,
+        spectra: Option<&mut EchoSpectra>,
+    ) {
+        match self {
+            Self::Fdaf {
+                state, snapshot, ..
+            } => {
+                // the single block filter always adapts, so what it learns
+                // is undone, along with the far end it has seen
+                if !adapt {
+                    snapshot.clone_from(state);
+                }
+                state.process(out, far, mic);
+                if !adapt {
+                    state.clone_from(snapshot);
+                }
+
+                // the filter does not share its spectra
+                if let Some(spectra) = spectra {
+                    let mut echo = [0f32; AEC_FRAME_SIZE];
+                    for ((echo, &mic), &out) in echo.iter_mut().zip(mic).zip(out.iter()) {
+                        *echo = mic - out;
+                    }
+                    spectra.analyze(&echo, out);
+                }
+            }
+            Self::Pbfdaf { state, estimator } => {
+                // the estimate is in samples, the filter is aligned in whole frames
+                if let Some(delay) = estimator.push(far, mic)
+                    && delay / AEC_FRAME_SIZE != state.delay()
+                {
+                    state.set_delay(delay / AEC_FRAME_SIZE);
+                }
+                state.process(out, far, mic, adapt);
+                if let Some(spectra) = spectra {
+                    spectra.echo = *state.echo_spectrum();
+                    spectra.error = *state.error_spectrum();
+                }
+            }
+        }
+    }
+
+    /// Frames of far end the echo in the microphone may come from.
+    pub fn echo_window(&self) -> usize {
+        match self {
+            // the delay is not known, look as far back as it may be
+            Self::Fdaf { .. } => AEC_MAX_DELAY_BLOCKS,
+            Self::Pbfdaf { state, .. } => state.delay() + AEC_PARTITIONS,
+        }
+    }
+
+    /// Drops what the filter has learned, keeping the far end alignment.
+    pub fn reset(&mut self) {
+        match self {
+            Self::Fdaf { state, init, .. } => state.clone_from(init),
+            Self::Pbfdaf { state, .. } => state.reset(),
+        }
+    }
+}
 
 pub struct CustomAudioProcessor {
     // Singal Process State Machines
     ref_limiter: SmoothLimiter,
     noise_gate: VoipSoftGate,
-    aec_init_state: FdafAec<AEC_FFT_SIZE>,
-    aec_state: FdafAec<AEC_FFT_SIZE>,
+    echo_filter: EchoFilter,
+    double_talk: DoubleTalkDetector,
     aec_guard: AecGuard,
+    suppressor: ResidualEchoSuppressor,
     denoise: Box<DenoiseState<'static>>,
     mic_hpfilter: DirectForm2Transposed<f32>,
     far_end_hpfilter: DirectForm2Transposed<f32>,
-    nlp_filter: DirectForm2Transposed<f32>,
 
-    // LocalRb
+    // Stage Buffers
 
     // Reference Limiter Buffer
-    ref_limit_prod: BufProd,
-    ref_limit_cons: BufCons,
+    ref_limit_prod: Producer<f32>,
+    ref_limit_cons: Consumer<f32>,
 
     // Dispatch Buffer
-    dispatch_prod: BufProd,
-    dispatch_cons: BufCons,
+    dispatch_prod: Producer<f32>,
+    dispatch_cons: Consumer<f32>,
 
     // HighPassFilter Mic Buffer
-    hpf_mic_prod: BufProd,
-    hpf_mic_cons: BufCons,
+    hpf_mic_prod: Producer<f32>,
+    hpf_mic_cons: Consumer<f32>,
 
     // HighPassFilter Ref Buffer
-    hpf_ref_prod: BufProd,
-    hpf_ref_cons: BufCons,
+    hpf_ref_prod: Producer<f32>,
+    hpf_ref_cons: Consumer<f32>,
 
     // AEC Buffer
-    aec_prod: BufProd,
-    aec_cons: BufCons,
+    aec_prod: Producer<f32>,
+    aec_cons: Consumer<f32>,
 
-    // NLP Buffer
-    nlp_prod: BufProd,
-    nlp_cons: BufCons,
+    // Noise Gate Buffer
+    gate_prod: Producer<f32>,
+    gate_cons: Consumer<f32>,
 }
 
 impl CustomAudioProcessor {
     pub fn build() -> Self {
+        Self::with_echo_config(EchoConfig::default())
+    }
+
+    pub fn with_echo_config(config: EchoConfig) -> Self {
         let coeffs = Coefficients::<f32>::from_params(
             Type::HighPass,
             FILTER_SAMPLE.hz(),
@@ -64,49 +192,39 @@ impl CustomAudioProcessor {
         )
         .expect("Failed to create coefficients");
 
-        // let nlp_lpfilter = Coefficients::<f32>::from_params(
-        // Type::LowPass,
-        // FILTER_SAMPLE.hz(),
-        // FILTER_HIGH_FRE.hz(),
-        // Q_BUTTERWORTH_F32,
-        // )
-        // .expect("Failed to create coefficients");
-
         // state machine init
         let ref_limiter = SmoothLimiter::new(0.9, 0.1, 80.0, SAMPLE_RATE as f32);
         let noise_gate = VoipSoftGate::new(0.01, 0.001, 1.0, 80.0, SAMPLE_RATE as f32);
-        let aec_state = FdafAec::<AEC_FFT_SIZE>::new(STEP_SIZE, 0.9, 10e-2, 10e-6);
+        let echo_filter = EchoFilter::new(config.canceller);
+        let double_talk =
+            DoubleTalkDetector::new(DTD_THRESHOLD, EchoFilter::MAX_ECHO_WINDOW, DTD_HANGOVER);
         let aec_guard = AecGuard::new(5, 30);
+        let suppressor = ResidualEchoSuppressor::new(config.suppression);
         let denoise = DenoiseState::new();
         let mic_hpfilter = DirectForm2Transposed::<f32>::new(coeffs);
         let far_end_hpfilter = DirectForm2Transposed::<f32>::new(coeffs);
-        let nlp_filter = DirectForm2Transposed::<f32>::new(coeffs);
 
-        // local ringbuffer
-        let (ref_limit_prod, ref_limit_cons) = LocalRb::<Heap<f32>>::new(FRAME_SIZE * 4).split();
-        let (dispatch_prod, dispatch_cons) = LocalRb::<Heap<f32>>::new(FRAME_SIZE * 4).split();
+        // stage ringbuffer
+        let (ref_limit_prod, ref_limit_cons) = RingBuffer::new(FRAME_SIZE * 4);
+        let (dispatch_prod, dispatch_cons) = RingBuffer::new(FRAME_SIZE * 4);
 
-        let (hpf_mic_prod, hpf_mic_cons) =
-            LocalRb::<Heap<f32>>::new(FRAME_SIZE.max(AEC_FRAME_SIZE) * 4).split();
-        let (hpf_ref_prod, hpf_ref_cons) =
-            LocalRb::<Heap<f32>>::new(FRAME_SIZE.max(AEC_FRAME_SIZE) * 4).split();
+        let (hpf_mic_prod, hpf_mic_cons) = RingBuffer::new(FRAME_SIZE.max(AEC_FRAME_SIZE) * 4);
+        let (hpf_ref_prod, hpf_ref_cons) = RingBuffer::new(FRAME_SIZE.max(AEC_FRAME_SIZE) * 4);
 
-        let (aec_prod, aec_cons) =
-            LocalRb::<Heap<f32>>::new(FRAME_SIZE.max(AEC_FRAME_SIZE) * 4).split();
+        let (aec_prod, aec_cons) = RingBuffer::new(FRAME_SIZE.max(AEC_FRAME_SIZE) * 4);
 
-        let (nlp_prod, nlp_cons) =
-            LocalRb::<Heap<f32>>::new(FRAME_SIZE.max(AEC_FRAME_SIZE) * 4).split();
+        let (gate_prod, gate_cons) = RingBuffer::new(FRAME_SIZE.max(AEC_FRAME_SIZE) * 4);
 
         Self {
             ref_limiter,
             noise_gate,
-            aec_init_state: aec_state.clone(),
-            aec_state,
+            echo_filter,
+            double_talk,
             aec_guard,
+            suppressor,
             denoise,
             mic_hpfilter,
             far_end_hpfilter,
-            nlp_filter,
             ref_limit_prod,
             ref_limit_cons,
             dispatch_prod,
@@ -117,18 +235,18 @@ impl CustomAudioProcessor {
             hpf_ref_cons,
             aec_prod,
             aec_cons,
-            nlp_prod,
-            nlp_cons,
+            gate_prod,
+            gate_cons,
         }
     }
 }
 impl AudioProcessor for CustomAudioProcessor {
     fn process(
         &mut self,
-        mic_cons: &mut HeapCons<f32>,
-        ref_cons: &mut HeapCons<f32>,
-        mic_prod: &mut HeapProd<f32>,
-        ref_prod: &mut HeapProd<f32>,
+        mic_cons: &mut Consumer<f32>,
+        ref_cons: &mut Consumer<f32>,
+        mic_prod: &mut Producer<f32>,
+        ref_prod: &mut Producer<f32>,
     ) {
         // pre process mic
         hpf(&mut self.mic_hpfilter, mic_cons, &mut self.hpf_mic_prod);
@@ -136,14 +254,14 @@ impl AudioProcessor for CustomAudioProcessor {
         limit(&mut self.ref_limiter, ref_cons, &mut self.ref_limit_prod);
 
         // ref dispatch
-        while self.ref_limit_cons.occupied_len() >= FRAME_SIZE
-            && ref_prod.vacant_len() >= FRAME_SIZE
-            && self.hpf_ref_prod.vacant_len() >= FRAME_SIZE
+        while self.ref_limit_cons.slots() >= FRAME_SIZE
+            && ref_prod.slots() >= FRAME_SIZE
+            && self.dispatch_prod.slots() >= FRAME_SIZE
         {
             let mut frame = [0f32; FRAME_SIZE];
-            self.ref_limit_cons.pop_slice(&mut frame);
-            ref_prod.push_slice(&frame);
-            self.dispatch_prod.push_slice(&frame);
+            pop_slice(&mut self.ref_limit_cons, &mut frame);
+            push_slice(ref_prod, &frame);
+            push_slice(&mut self.dispatch_prod, &frame);
         }
 
         // pre process far end ref HighPassFilter
@@ -155,121 +273,114 @@ impl AudioProcessor for CustomAudioProcessor {
 
         // aec (echo cancel)
         aec(
-            &mut self.aec_state,
-            &self.aec_init_state,
+            &mut self.echo_filter,
+            &mut self.double_talk,
             &mut self.aec_guard,
+            &mut self.suppressor,
             &mut self.hpf_mic_cons,
             &mut self.hpf_ref_cons,
             &mut self.aec_prod,
         );
 
-        nlp(
-            &mut self.nlp_filter,
+        gate(
             &mut self.noise_gate,
             &mut self.aec_cons,
-            &mut self.nlp_prod,
+            &mut self.gate_prod,
         );
 
-        noiseless(&mut self.denoise, &mut self.nlp_cons, mic_prod);
+        noiseless(&mut self.denoise, &mut self.gate_cons, mic_prod);
     }
 }
 
 pub fn hpf(
     filter: &mut DirectForm2Transposed<f32>,
-    cons: &mut impl Consumer<Item = f32>,
-    prod: &mut impl Producer<Item = f32>,
+    cons: &mut Consumer<f32>,
+    prod: &mut Producer<f32>,
 ) {
     let mut hpf_frame = [0f32; FRAME_SIZE];
 
-    while cons.occupied_len() >= FRAME_SIZE && prod.vacant_len() >= FRAME_SIZE {
-        cons.pop_slice(&mut hpf_frame);
+    while cons.slots() >= FRAME_SIZE && prod.slots() >= FRAME_SIZE {
+        pop_slice(cons, &mut hpf_frame);
         sanitize(&mut hpf_frame);
         for i in hpf_frame.iter_mut() {
             *i = filter.run(*i);
         }
-        prod.push_slice(&hpf_frame);
+        push_slice(prod, &hpf_frame);
     }
 }
 
-pub fn limit(
-    limiter: &mut SmoothLimiter,
-    cons: &mut impl Consumer<Item = f32>,
-    prod: &mut impl Producer<Item = f32>,
-) {
+pub fn limit(limiter: &mut SmoothLimiter, cons: &mut Consumer<f32>, prod: &mut Producer<f32>) {
     let mut frame = [0f32; FRAME_SIZE];
-    while cons.occupied_len() >= FRAME_SIZE && prod.vacant_len() >= FRAME_SIZE {
-        cons.pop_slice(&mut frame);
+    while cons.slots() >= FRAME_SIZE && prod.slots() >= FRAME_SIZE {
+        pop_slice(cons, &mut frame);
         sanitize(&mut frame);
         limiter.process(&mut frame);
-        prod.push_slice(&frame);
+        push_slice(prod, &frame);
     }
 }
 
-#[allow(clippy::unwrap_used)]
 pub fn aec(
-    aec: &mut FdafAec<AEC_FFT_SIZE>,
-    inited_aec: &FdafAec<AEC_FFT_SIZE>,
+    aec: &mut EchoFilter,
+    double_talk: &mut DoubleTalkDetector,
     guard: &mut AecGuard,
-    mic_cons: &mut impl Consumer<Item = f32>,
-    ref_cons: &mut impl Consumer<Item = f32>,
-    prod: &mut impl Producer<Item = f32>,
+    suppressor: &mut ResidualEchoSuppressor,
+    mic_cons: &mut Consumer<f32>,
+    ref_cons: &mut Consumer<f32>,
+    prod: &mut Producer<f32>,
 ) {
     let mut mic_frame = [0f32; AEC_FRAME_SIZE];
     let mut ref_frame = [0f32; AEC_FRAME_SIZE];
     let mut output_frame = [0f32; AEC_FRAME_SIZE];
 
-    while mic_cons.occupied_len() >= AEC_FRAME_SIZE && prod.vacant_len() >= AEC_FRAME_SIZE {
-        mic_cons.pop_slice(&mut mic_frame);
-        if ref_cons.occupied_len() >= AEC_FRAME_SIZE {
-            ref_cons.pop_slice(&mut ref_frame);
+    while mic_cons.slots() >= AEC_FRAME_SIZE && prod.slots() >= AEC_FRAME_SIZE {
+        pop_slice(mic_cons, &mut mic_frame);
+        if ref_cons.slots() >= AEC_FRAME_SIZE {
+            pop_slice(ref_cons, &mut ref_frame);
         } else {
             ref_frame = [0.0; AEC_FRAME_SIZE];
         }
 
+        double_talk.set_window(aec.echo_window());
+        let is_double_talk = double_talk.process(&ref_frame, &mic_frame);
         aec.process(
-            output_frame.first_chunk_mut::<AEC_FRAME_SIZE>().unwrap(),
-            ref_frame.first_chunk().unwrap(),
-            mic_frame.first_chunk().unwrap(),
+            &mut output_frame,
+            &ref_frame,
+            &mic_frame,
+            !is_double_talk,
+            suppressor.spectra(),
         );
 
-        if guard.examine_and_protect(&mic_frame, &mut output_frame) {
-            *aec = inited_aec.clone();
+        if guard.examine_and_protect(&mic_frame, &mut output_frame, is_double_talk) {
+            aec.reset();
+        }
+        // the filter's error is not what is sent then
+        if guard.protected()
+            && let Some(spectra) = suppressor.spectra()
+        {
+            spectra.analyze_error(&output_frame);
         }
-        prod.push_slice(&output_frame);
+        suppressor.process(&mut output_frame, is_double_talk);
+        push_slice(prod, &output_frame);
     }
 }
 
-pub fn nlp(
-    nlp_filter: &mut DirectForm2Transposed<f32>,
-    noise_gate: &mut VoipSoftGate,
-    cons: &mut impl Consumer<Item = f32>,
-    prod: &mut impl Producer<Item = f32>,
-) {
-    let mut nlp_frame = [0f32; FRAME_SIZE];
+pub fn gate(noise_gate: &mut VoipSoftGate, cons: &mut Consumer<f32>, prod: &mut Producer<f32>) {
+    let mut gate_frame = [0f32; FRAME_SIZE];
 
-    while cons.occupied_len() >= FRAME_SIZE && prod.vacant_len() >= FRAME_SIZE {
-        cons.pop_slice(&mut nlp_frame);
-        for i in nlp_frame.iter_mut() {
-            *i = nlp_filter.run(*i);
-        }
-        noise_gate.process(&mut nlp_frame);
-        sanitize(&mut nlp_frame);
-        prod.push_slice(&nlp_frame);
+    while cons.slots() >= FRAME_SIZE && prod.slots() >= FRAME_SIZE {
+        pop_slice(cons, &mut gate_frame);
+        noise_gate.process(&mut gate_frame);
+        sanitize(&mut gate_frame);
+        push_slice(prod, &gate_frame);
     }
 }
 
-pub fn noiseless(
-    denoise: &mut DenoiseState,
-    cons: &mut impl Consumer<Item = f32>,
-    prod: &mut impl Producer<Item = f32>,
-) {
+pub fn noiseless(denoise: &mut DenoiseState, cons: &mut Consumer<f32>, prod: &mut Producer<f32>) {
     let mut ns_input_frame = [0.0; DenoiseState::FRAME_SIZE];
     let mut ns_output_frame = [0.0; DenoiseState::FRAME_SIZE];
 
-    while cons.occupied_len() >= DenoiseState::FRAME_SIZE
-        && prod.vacant_len() >= DenoiseState::FRAME_SIZE
-    {
-        cons.pop_slice(&mut ns_input_frame);
+    while cons.slots() >= DenoiseState::FRAME_SIZE && prod.slots() >= DenoiseState::FRAME_SIZE {
+        pop_slice(cons, &mut ns_input_frame);
 
         for i in ns_input_frame.iter_mut() {
             *i *= 32767.0f32;
@@ -282,7 +393,7 @@ pub fn noiseless(
         }
 
         sanitize(&mut ns_output_frame);
-        prod.push_slice(&ns_output_frame);
+        push_slice(prod, &ns_output_frame);
     }
 }
 
@@ -292,3 +403,20 @@ pub fn sanitize(frame: &mut [f32]) {
         *x = val.clamp(-1.0, 1.0);
     }
 }
+
+/// Takes `frame.len()` samples, which the caller has checked are queued.
+fn pop_slice(cons: &mut Consumer<f32>, frame: &mut [f32]) {
+    if let Ok(chunk) = cons.read_chunk(frame.len()) {
+        let (first, second) = chunk.as_slices();
+        frame[..first.len()].copy_from_slice(first);
+        frame[first.len()..].copy_from_slice(second);
+        chunk.commit_all();
+    }
+}
+
+/// Queues `frame`, which the caller has checked there is room for.
+fn push_slice(prod: &mut Producer<f32>, frame: &[f32]) {
+    if let Ok(chunk) = prod.write_chunk_uninit(frame.len()) {
+        chunk.fill_from_iter(frame.iter().copied());
+    }
+}
@@ -5,6 +5,10 @@ pub const RB_SIZE: usize = FRAME_SIZE * 4;
 pub const AEC_FRAME_SIZE: usize = 512;
 pub const AEC_FFT_SIZE: usize = AEC_FRAME_SIZE * 2;
 pub const STEP_SIZE: f32 = 0.1;
+pub const AEC_PARTITIONS: usize = 8;
+pub const AEC_MAX_DELAY_BLOCKS: usize = 32; // ~340ms
+pub const DTD_THRESHOLD: f32 = 0.5; // 6dB echo return loss
+pub const DTD_HANGOVER: usize = 20; // ~210ms
 
 pub const FILTER_SAMPLE: f32 = SAMPLE_RATE as f32;
 pub const FILTER_LOW_FRE: f32 = 100f32;
@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+
+/// Samples averaged into one envelope point, 750 points per second.
+const DECIMATION: usize = 64;
+/// Envelope points correlated per estimate, 2 s.
+const WINDOW: usize = 1500;
+/// Frames between two estimates.
+const ESTIMATE_INTERVAL: usize = 16;
+/// Correlation below which the far end is not heard in the microphone.
+const MIN_CORRELATION: f32 = 0.5;
+/// Equal estimates in a row before the delay is changed.
+const CONFIRMATIONS: usize = 3;
+/// Far-end envelope variance below which it is too quiet to correlate.
+const MIN_FAR_VARIANCE: f32 = 1e-8;
+
+/// Measures how long the far end takes from the speaker back into the
+/// microphone, by cross-correlating the decimated envelopes of both.
+///
+/// Envelopes are insensitive to phase, so the echo path's filtering does not
+/// hide the peak, and decimating them keeps a search over long delays cheap.
+pub struct DelayEstimator {
+    max_lag: usize,
+    far: VecDeque<f32>,
+    near: VecDeque<f32>,
+    far_acc: f32,
+    near_acc: f32,
+    acc_len: usize,
+    frames: usize,
+    candidate: Option<usize>,
+    confirmations: usize,
+    delay: Option<usize>,
+}
+
+impl DelayEstimator {
+    /// `max_delay`: longest delay searched, in samples.
+    pub fn new(max_delay: usize) -> Self {
+        let max_lag = max_delay / DECIMATION;
+        Self {
+            max_lag,
+            far: VecDeque::with_capacity(WINDOW + max_lag),
+            near: VecDeque::with_capacity(WINDOW),
+            far_acc: 0.0,
+            near_acc: 0.0,
+            acc_len: 0,
+            frames: 0,
+            candidate: None,
+            confirmations: 0,
+            delay: None,
+        }
+    }
+
+    /// Last delay settled on, in samples.
+    pub fn delay(&self) -> Option<usize> {
+        self.delay
+    }
+
+    /// Feeds one frame of the far end and of the microphone, captured at the
+    /// same time. Returns the delay, in samples, when it has changed.
+    pub fn push(&mut self, far: &[f32], near: &[f32]) -> Option<usize> {
+        for (&far, &near) in far.iter().zip(near.iter()) {
+            self.far_acc += far.abs();
+            self.near_acc += near.abs();
+            self.acc_len += 1;
+            if self.acc_len == DECIMATION {
+                push_point(
+                    &mut self.far,
+                    self.far_acc / DECIMATION as f32,
+                    WINDOW + self.max_lag,
+                );
+                push_point(&mut self.near, self.near_acc / DECIMATION as f32, WINDOW);
+                self.far_acc = 0.0;
+                self.near_acc = 0.0;
+                self.acc_len = 0;
+            }
+        }
+
+        self.frames += 1;
+        if self.frames < ESTIMATE_INTERVAL || self.near.len() < WINDOW {
+            return None;
+        }
+        self.frames = 0;
+
+        let lag = self.best_lag()?;
+        if self.candidate == Some(lag) {
+            self.confirmations += 1;
+        } else {
+            self.candidate = Some(lag);
+            self.confirmations = 1;
+        }
+        let delay = lag * DECIMATION;
+        if self.confirmations < CONFIRMATIONS || self.delay == Some(delay) {
+            return None;
+        }
+        self.delay = Some(delay);
+        self.delay
+    }
+
+    /// Lag, in envelope points, at which the far end best explains the
+    /// microphone. `None` while the far end is silent or not heard.
+    fn best_lag(&mut self) -> Option<usize> {
+        // rotated in place, this runs on the audio thread and must not allocate
+        let near = &*self.near.make_contiguous();
+        let far = &*self.far.make_contiguous();
+        // the newest near point lines up with the newest far point at lag 0
+        let offset = far.len() - near.len();
+
+        let (near_mean, near_var) = mean_variance(near);
+        if near_var <= 0.0 {
+            return None;
+        }
+
+        let mut best: Option<(usize, f32)> = None;
+        for lag in 0..=self.max_lag.min(offset) {
+            let start = offset - lag;
+            let far = &far[start..start + near.len()];
+            let (far_mean, far_var) = mean_variance(far);
+            if far_var < MIN_FAR_VARIANCE {
+                continue;
+            }
+            let covariance = near
+                .iter()
+                .zip(far.iter())
+                .map(|(&near, &far)| (near - near_mean) * (far - far_mean))
+                .sum::<f32>()
+                / near.len() as f32;
+            let correlation = covariance / (near_var * far_var).sqrt();
+            if best.is_none_or(|(_, best)| correlation > best) {
+                best = Some((lag, correlation));
+            }
+        }
+
+        let (lag, correlation) = best?;
+        (correlation >= MIN_CORRELATION).then_some(lag)
+    }
+}
+
+fn push_point(points: &mut VecDeque<f32>, point: f32, capacity: usize) {
+    if points.len() == capacity {
+        points.pop_front();
+    }
+    points.push_back(point);
+}
+
+fn mean_variance(points: &[f32]) -> (f32, f32) {
+    let mean = points.iter().sum::<f32>() / points.len() as f32;
+    let variance = points
+        .iter()
+        .map(|&point| (point - mean) * (point - mean))
+        .sum::<f32>()
+        / points.len() as f32;
+    (mean, variance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: usize = 512;
+
+    /// Noise whose level changes every few hundred samples, like speech,
+    /// so that its envelope has something to correlate.
+    fn speech_like(len: usize) -> Vec<f32> {
+        let mut state = 0x2545_f491_u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as f32 / u32::MAX as f32 * 2.0 - 1.0
+        };
+        let mut level = 0.0;
+        (0..len)
+            .map(|i| {
+                if i % 700 == 0 {
+                    level = next().abs();
+                }
+                next() * level
+            })
+            .collect()
+    }
+
+    /// Feeds `far` and `near` frame by frame, returning the last change.
+    fn estimate(estimator: &mut DelayEstimator, far: &[f32], near: &[f32]) -> Option<usize> {
+        far.chunks_exact(FRAME)
+            .zip(near.chunks_exact(FRAME))
+            .filter_map(|(far, near)| estimator.push(far, near))
+            .last()
+    }
+
+    #[test]
+    fn finds_a_known_delay() {
+        let delay = 4800;
+        let far = speech_like(48_000 * 5);
+        // the room returns half of the far end, 100 ms later
+        let near: Vec<f32> = (0..far.len())
+            .map(|i| i.checked_sub(delay).map_or(0.0, |i| 0.5 * far[i]))
+            .collect();
+
+        let mut estimator = DelayEstimator::new(FRAME * 32);
+        let found = estimate(&mut estimator, &far, &near).expect("no delay found");
+        assert!(found.abs_diff(delay) <= DECIMATION, "found {found}");
+        assert_eq!(estimator.delay(), Some(found));
+    }
+
+    #[test]
+    fn follows_a_delay_change() {
+        let far = speech_like(48_000 * 10);
+        let near: Vec<f32> = (0..far.len())
+            .map(|i| {
+                // the echo path grows from 50 ms to 150 ms half way
+                let delay = if i < far.len() / 2 { 2400 } else { 7200 };
+                i.checked_sub(delay).map_or(0.0, |i| 0.5 * far[i])
+            })
+            .collect();
+
+        let mut estimator = DelayEstimator::new(FRAME * 32);
+        let found = estimate(&mut estimator, &far, &near).expect("no delay found");
+        assert!(found.abs_diff(7200) <= DECIMATION, "found {found}");
+    }
+
+    #[test]
+    fn stays_unset_without_echo() {
+        let far = speech_like(48_000 * 5);
+        let near = vec![0.0; far.len()];
+        let mut estimator = DelayEstimator::new(FRAME * 32);
+        assert_eq!(estimate(&mut estimator, &far, &near), None);
+
+        // nor when the far end is silent and only the near end talks
+        let mut estimator = DelayEstimator::new(FRAME * 32);
+        assert_eq!(estimate(&mut estimator, &near, &far), None);
+        assert_eq!(estimator.delay(), None);
+    }
+}
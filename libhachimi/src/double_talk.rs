@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+
+/// Far-end peak below which there is no echo to tell near-end speech from,
+/// about -80 dBFS.
+const SILENT_FAR_PEAK: f32 = 1e-4;
+
+/// Geigel double-talk detector with hangover.
+///
+/// The echo cannot be louder than `threshold` times the loudest far-end
+/// sample that could still be ringing in the room, so a microphone peak above
+/// that is near-end speech. Detection is held for `hangover` frames, to cover
+/// the quiet gaps between words.
+#[derive(Debug, Clone)]
+pub struct DoubleTalkDetector {
+    threshold: f32,
+    hangover: usize,
+    hangover_remaining: usize,
+    window: usize,
+    max_window: usize,
+    far_peaks: VecDeque<f32>,
+}
+
+impl DoubleTalkDetector {
+    /// `threshold`: largest echo path gain expected, 0.5 is 6 dB of echo return loss.
+    /// `max_window`: most frames of far end the echo may still come from,
+    /// the window starts there.
+    /// `hangover`: frames detection is held after the last detected one.
+    pub fn new(threshold: f32, max_window: usize, hangover: usize) -> Self {
+        let max_window = max_window.max(1);
+        Self {
+            threshold,
+            hangover,
+            hangover_remaining: 0,
+            window: max_window,
+            max_window,
+            // set_window never grows it past that, on the audio thread
+            far_peaks: VecDeque::with_capacity(max_window),
+        }
+    }
+
+    /// Follows the echo path when the far end alignment changes, up to the
+    /// maximum window.
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window.clamp(1, self.max_window);
+        while self.far_peaks.len() > self.window {
+            self.far_peaks.pop_front();
+        }
+    }
+
+    pub fn is_double_talk(&self) -> bool {
+        self.hangover_remaining > 0
+    }
+
+    /// Feeds one frame of the far end and of the microphone, captured at the
+    /// same time. Returns whether both sides are talking.
+    pub fn process(&mut self, far: &[f32], mic: &[f32]) -> bool {
+        if self.far_peaks.len() == self.window {
+            self.far_peaks.pop_front();
+        }
+        self.far_peaks.push_back(peak(far));
+
+        let far_peak = self.far_peaks.iter().copied().fold(0.0f32, f32::max);
+        // with nothing to echo, the near end talking alone is no double talk
+        if far_peak > SILENT_FAR_PEAK && peak(mic) > self.threshold * far_peak {
+            self.hangover_remaining = self.hangover + 1;
+        } else {
+            self.hangover_remaining = self.hangover_remaining.saturating_sub(1);
+        }
+        self.is_double_talk()
+    }
+}
+
+fn peak(frame: &[f32]) -> f32 {
+    frame.iter().fold(0.0f32, |peak, x| peak.max(x.abs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: usize = 512;
+    const HANGOVER: usize = 3;
+
+    fn tone(frequency: f32, amplitude: f32) -> Vec<f32> {
+        (0..FRAME)
+            .map(|i| amplitude * (i as f32 * frequency * std::f32::consts::TAU / 48_000.0).sin())
+            .collect()
+    }
+
+    fn detector() -> DoubleTalkDetector {
+        DoubleTalkDetector::new(0.5, 4, HANGOVER)
+    }
+
+    #[test]
+    fn far_end_alone_is_not_double_talk() {
+        let mut detector = detector();
+        let far = tone(440.0, 0.8);
+        // the room returns a quarter of it
+        let echo: Vec<f32> = far.iter().map(|x| x * 0.25).collect();
+        for _ in 0..20 {
+            assert!(!detector.process(&far, &echo));
+        }
+    }
+
+    #[test]
+    fn near_end_alone_is_not_double_talk() {
+        let mut detector = detector();
+        let silence = vec![0.0; FRAME];
+        let near = tone(220.0, 0.5);
+        for _ in 0..20 {
+            assert!(!detector.process(&silence, &near));
+        }
+    }
+
+    #[test]
+    fn both_talking_is_double_talk_held_for_the_hangover() {
+        let mut detector = detector();
+        let far = tone(440.0, 0.4);
+        let both: Vec<f32> = far
+            .iter()
+            .zip(tone(220.0, 0.6))
+            .map(|(far, near)| far * 0.25 + near)
+            .collect();
+        let echo: Vec<f32> = far.iter().map(|x| x * 0.25).collect();
+
+        assert!(detector.process(&far, &both));
+        assert!(detector.is_double_talk());
+        for _ in 0..HANGOVER {
+            assert!(detector.process(&far, &echo));
+        }
+        assert!(!detector.process(&far, &echo));
+    }
+
+    #[test]
+    fn echo_of_an_earlier_frame_stays_within_the_window() {
+        let mut detector = detector();
+        let far = tone(440.0, 0.8);
+        let silence = vec![0.0; FRAME];
+        let echo: Vec<f32> = far.iter().map(|x| x * 0.25).collect();
+
+        detector.process(&far, &silence);
+        // the far end stopped, its echo is still ringing
+        assert!(!detector.process(&silence, &echo));
+    }
+
+    #[test]
+    fn window_never_grows_past_the_reserved_one() {
+        let mut detector = detector();
+        let capacity = detector.far_peaks.capacity();
+        detector.set_window(100);
+        let far = tone(440.0, 0.8);
+        for _ in 0..10 {
+            detector.process(&far, &far);
+        }
+        assert_eq!(detector.far_peaks.len(), 4);
+        assert_eq!(detector.far_peaks.capacity(), capacity);
+    }
+}
@@ -1,14 +1,18 @@
-#![no_std]
-extern crate alloc;
-
 pub mod aec_guard;
+#[cfg(feature = "pipeline")]
 pub mod audio_processing;
 pub mod constant;
-pub mod error;
+pub mod delay_estimator;
+pub mod double_talk;
 pub mod limiter;
 pub mod noise_gate;
+#[cfg(feature = "pipeline")]
+pub mod residual_echo;
+#[cfg(feature = "pipeline")]
 pub mod try_impl_aec;
 
+/// Turns the raw microphone and the far end into what is sent and what is
+/// played. Consumes whatever whole frames are queued on each call.
 pub trait AudioProcessor {
     fn process(
         &mut self,
@@ -0,0 +1,231 @@
+use num_complex::Complex32;
+use rustfft::{Fft, FftPlanner};
+use std::sync::Arc;
+
+use crate::constant::{AEC_FFT_SIZE, AEC_FRAME_SIZE};
+
+/// Smoothing of the error and echo power spectra.
+const PSD_ALPHA: f32 = 0.7;
+/// Smoothing of the per-bin echo leakage.
+const LEAK_ALPHA: f32 = 0.95;
+/// Leakage assumed before any has been measured, -5 dB.
+const INITIAL_LEAK: f32 = 0.3;
+/// Echo power below which a bin tells nothing about the leakage.
+const MIN_ECHO_POWER: f32 = 1e-3;
+const EPS: f32 = 1e-9;
+
+/// How hard the residual echo is suppressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SuppressionLevel {
+    /// Only the linear filter removes echo, without the added frame of delay.
+    Off,
+    /// Keeps near-end speech intact, leaves some echo.
+    Low,
+    #[default]
+    Moderate,
+    /// Removes echo on loud speakers, at the cost of clipped double talk.
+    High,
+}
+
+impl SuppressionLevel {
+    /// `(overdrive, floor)`: how much the residual estimate is overweighted,
+    /// and the lowest gain applied to a bin.
+    fn params(self) -> (f32, f32) {
+        match self {
+            Self::Off => (0.0, 1.0),
+            Self::Low => (1.0, 0.3),
+            Self::Moderate => (2.0, 0.1),
+            Self::High => (4.0, 0.03),
+        }
+    }
+}
+
+/// Spectra of one frame of the adaptive filter, as the suppressor reads them.
+pub struct EchoSpectra {
+    fft: Arc<dyn Fft<f32>>,
+    /// Echo estimate, in the second half once transformed back.
+    pub echo: [Complex32; AEC_FFT_SIZE],
+    /// Error, in the second half once transformed back, the first is zero.
+    pub error: [Complex32; AEC_FFT_SIZE],
+}
+
+impl EchoSpectra {
+    fn new() -> Self {
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(AEC_FFT_SIZE),
+            echo: [Complex32::default(); AEC_FFT_SIZE],
+            error: [Complex32::default(); AEC_FFT_SIZE],
+        }
+    }
+
+    /// For a filter that keeps its spectra to itself.
+    pub fn analyze(&mut self, echo: &[f32; AEC_FRAME_SIZE], error: &[f32; AEC_FRAME_SIZE]) {
+        Self::transform(&self.fft, &mut self.echo, echo);
+        self.analyze_error(error);
+    }
+
+    /// When the error was replaced after the filter.
+    pub fn analyze_error(&mut self, error: &[f32; AEC_FRAME_SIZE]) {
+        Self::transform(&self.fft, &mut self.error, error);
+    }
+
+    fn transform(
+        fft: &Arc<dyn Fft<f32>>,
+        spectrum: &mut [Complex32; AEC_FFT_SIZE],
+        frame: &[f32; AEC_FRAME_SIZE],
+    ) {
+        let (zeros, tail) = spectrum.split_at_mut(AEC_FRAME_SIZE);
+        zeros.fill(Complex32::default());
+        for (bin, &x) in tail.iter_mut().zip(frame) {
+            *bin = Complex32::new(x, 0.0);
+        }
+        fft.process(spectrum);
+    }
+}
+
+/// Frequency-domain suppressor for the echo the linear filter leaves behind.
+///
+/// The residual in each bin is estimated as a share of the filter's echo
+/// estimate. That share is learned while only the far end talks, then bins
+/// where the residual dominates the error are attenuated. The error frame
+/// sits behind zeros, so what the gains smear ahead of it lands on the frame
+/// before, which delays the output by one frame.
+pub struct ResidualEchoSuppressor {
+    level: SuppressionLevel,
+    overdrive: f32,
+    floor: f32,
+    fft_inv: Arc<dyn Fft<f32>>,
+    spectra: EchoSpectra,
+    suppressed: [Complex32; AEC_FFT_SIZE],
+    overlap: [f32; AEC_FRAME_SIZE],
+    error_psd: [f32; AEC_FFT_SIZE],
+    echo_psd: [f32; AEC_FFT_SIZE],
+    leak: [f32; AEC_FFT_SIZE],
+}
+
+impl ResidualEchoSuppressor {
+    pub fn new(level: SuppressionLevel) -> Self {
+        let (overdrive, floor) = level.params();
+
+        Self {
+            level,
+            overdrive,
+            floor,
+            fft_inv: FftPlanner::new().plan_fft_inverse(AEC_FFT_SIZE),
+            spectra: EchoSpectra::new(),
+            suppressed: [Complex32::default(); AEC_FFT_SIZE],
+            overlap: [0.0; AEC_FRAME_SIZE],
+            error_psd: [0.0; AEC_FFT_SIZE],
+            echo_psd: [0.0; AEC_FFT_SIZE],
+            leak: [INITIAL_LEAK; AEC_FFT_SIZE],
+        }
+    }
+
+    /// Where the echo canceller leaves the spectra of each frame, none when
+    /// suppression is off.
+    pub fn spectra(&mut self) -> Option<&mut EchoSpectra> {
+        (self.level != SuppressionLevel::Off).then_some(&mut self.spectra)
+    }
+
+    /// `output`: the suppressed error of the frame before the spectra, left
+    /// alone when suppression is off.
+    /// `double_talk`: the leakage is not learned while the near end talks.
+    pub fn process(&mut self, output: &mut [f32; AEC_FRAME_SIZE], double_talk: bool) {
+        if self.level == SuppressionLevel::Off {
+            return;
+        }
+
+        let spectra = &self.spectra;
+
+        for f in 0..AEC_FFT_SIZE {
+            let error_power = spectra.error[f].norm_sqr();
+            let echo_power = spectra.echo[f].norm_sqr();
+            self.error_psd[f] = PSD_ALPHA * self.error_psd[f] + (1.0 - PSD_ALPHA) * error_power;
+            self.echo_psd[f] = PSD_ALPHA * self.echo_psd[f] + (1.0 - PSD_ALPHA) * echo_power;
+
+            // with only the far end talking, whatever is left is echo
+            if !double_talk && self.echo_psd[f] > MIN_ECHO_POWER {
+                let leak = (self.error_psd[f] / self.echo_psd[f]).min(1.0);
+                self.leak[f] = LEAK_ALPHA * self.leak[f] + (1.0 - LEAK_ALPHA) * leak;
+            }
+
+            let residual = self.leak[f] * self.echo_psd[f];
+            let gain = 1.0 - self.overdrive * residual / (self.error_psd[f] + EPS);
+            self.suppressed[f] = spectra.error[f] * gain.clamp(self.floor, 1.0);
+        }
+
+        self.fft_inv.process(&mut self.suppressed);
+        let inv_n = 1.0 / AEC_FFT_SIZE as f32;
+        let (head, tail) = self.suppressed.split_at(AEC_FRAME_SIZE);
+        for (((out, overlap), head), tail) in
+            output.iter_mut().zip(&mut self.overlap).zip(head).zip(tail)
+        {
+            *out = *overlap + head.re * inv_n;
+            *overlap = tail.re * inv_n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(frame: usize, frequency: f32, amplitude: f32) -> [f32; AEC_FRAME_SIZE] {
+        core::array::from_fn(|i| {
+            let t = (frame * AEC_FRAME_SIZE + i) as f32 / 48_000.0;
+            amplitude * (t * frequency * core::f32::consts::TAU).sin()
+        })
+    }
+
+    fn energy(frame: &[f32]) -> f32 {
+        frame.iter().map(|x| x * x).sum()
+    }
+
+    #[test]
+    fn attenuates_a_pure_echo() {
+        let mut suppressor = ResidualEchoSuppressor::new(SuppressionLevel::Moderate);
+        let mut input = 0.0;
+        let mut output = 0.0;
+        for frame in 0..100 {
+            let echo = tone(frame, 700.0, 0.5);
+            // the linear filter left a third of it
+            let residual = echo.map(|x| x / 3.0);
+            suppressor.spectra().unwrap().analyze(&echo, &residual);
+            let mut out = [0.0; AEC_FRAME_SIZE];
+            suppressor.process(&mut out, false);
+            if frame >= 50 {
+                input += energy(&residual);
+                output += energy(&out);
+            }
+        }
+        // at least 15 dB down
+        assert!(output < input * 0.03, "{output} of {input}");
+    }
+
+    #[test]
+    fn passes_near_end_speech_a_frame_late() {
+        let mut suppressor = ResidualEchoSuppressor::new(SuppressionLevel::High);
+        let silence = [0.0; AEC_FRAME_SIZE];
+        let mut last = silence;
+        for frame in 0..20 {
+            let speech = tone(frame, 300.0, 0.5);
+            suppressor.spectra().unwrap().analyze(&silence, &speech);
+            let mut out = [0.0; AEC_FRAME_SIZE];
+            suppressor.process(&mut out, true);
+            for (out, last) in out.iter().zip(&last) {
+                assert!((out - last).abs() < 1e-4);
+            }
+            last = speech;
+        }
+    }
+
+    #[test]
+    fn off_reads_no_spectra_and_leaves_the_output() {
+        let mut suppressor = ResidualEchoSuppressor::new(SuppressionLevel::Off);
+        assert!(suppressor.spectra().is_none());
+        let speech = tone(0, 300.0, 0.5);
+        let mut out = speech;
+        suppressor.process(&mut out, false);
+        assert_eq!(out, speech);
+    }
+}
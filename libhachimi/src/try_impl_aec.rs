@@ -1,6 +1,6 @@
-use alloc::sync::Arc;
 use num_complex::Complex32;
 use rustfft::{Fft, FftPlanner};
+use std::sync::Arc;
 
 /// Production-grade PBFDAF AEC
 /// L: Frame size (512)
@@ -8,11 +8,13 @@ use rustfft::{Fft, FftPlanner};
 /// K: Number of blocks (4-8)
 /// MAX_D: Max delay blocks
 pub struct PbfdafAec<const L: usize, const N: usize, const K: usize, const MAX_D: usize> {
-    w: [[Complex32; N]; K],      // Filter weights (Freq domain)
-    x_hist: [[Complex32; N]; K], // Reference history (Freq domain)
-    hist_ptr: usize,             // Circular pointer for x_hist
-    power_est: [f32; N],         // Power Spectral Density estimate
-    last_ref: [f32; L],          // Previous ref frame for Overlap-Save
+    w: [[Complex32; N]; K],         // Filter weights (Freq domain)
+    x_hist: [[Complex32; N]; K],    // Reference history (Freq domain)
+    hist_ptr: usize,                // Circular pointer for x_hist
+    power_est: [f32; N],            // Power Spectral Density estimate
+    last_ref: [f32; L],             // Previous ref frame for Overlap-Save
+    echo_spectrum: [Complex32; N],  // Echo estimate of the last frame
+    error_spectrum: [Complex32; N], // Its error, behind L zeros
 
     delay_ring: [[f32; L]; MAX_D], // Fixed-size delay buffer
     delay_rd: usize,
@@ -41,6 +43,8 @@ impl<const L: usize, const N: usize, const K: usize, const MAX_D: usize> PbfdafA
             hist_ptr: 0,
             power_est: [0.5; N],
             last_ref: [0.0; L],
+            echo_spectrum: [Complex32::default(); N],
+            error_spectrum: [Complex32::default(); N],
             delay_ring: [[0.0; L]; MAX_D],
             delay_rd: 0,
             delay_wr: initial_delay % MAX_D,
@@ -54,11 +58,51 @@ impl<const L: usize, const N: usize, const K: usize, const MAX_D: usize> PbfdafA
         }
     }
 
+    /// Delays the reference by `blocks` frames, moving the read side of the
+    /// delay ring so the change takes effect on the next frame.
     pub fn set_delay(&mut self, blocks: usize) {
-        self.target_delay = blocks % MAX_D;
+        self.target_delay = blocks.min(MAX_D - 1);
+        self.delay_rd = (self.delay_wr + MAX_D - self.target_delay) % MAX_D;
     }
 
-    pub fn process(&mut self, error_out: &mut [f32; L], raw_ref: &[f32; L], mic: &[f32; L]) {
+    pub fn delay(&self) -> usize {
+        self.target_delay
+    }
+
+    /// Spectrum of the echo estimate of the last frame, in its second half
+    /// once transformed back.
+    pub fn echo_spectrum(&self) -> &[Complex32; N] {
+        &self.echo_spectrum
+    }
+
+    /// Spectrum of the error of the last frame, preceded by L zeros.
+    pub fn error_spectrum(&self) -> &[Complex32; N] {
+        &self.error_spectrum
+    }
+
+    /// Forgets what the filter has learned, in place. The delay and the far
+    /// end still in the delay ring are kept.
+    pub fn reset(&mut self) {
+        for w in self.w.iter_mut() {
+            w.fill(Complex32::default());
+        }
+        for x in self.x_hist.iter_mut() {
+            x.fill(Complex32::default());
+        }
+        self.hist_ptr = 0;
+        self.power_est.fill(0.5);
+        self.last_ref.fill(0.0);
+    }
+
+    /// `adapt`: false while the near end talks, so its speech does not pull
+    /// the filter away from the echo path.
+    pub fn process(
+        &mut self,
+        error_out: &mut [f32; L],
+        raw_ref: &[f32; L],
+        mic: &[f32; L],
+        adapt: bool,
+    ) {
         let inv_n = 1.0 / N as f32;
 
         // 1. Delay Buffer Management
@@ -93,58 +137,60 @@ impl<const L: usize, const N: usize, const K: usize, const MAX_D: usize> PbfdafA
             }
         }
 
+        self.echo_spectrum = y_fft;
         let mut y_time = y_fft;
         self.fft_inv.process(&mut y_time);
 
         // 4. Error Calculation (Time Domain)
-        let mut mic_eng = 1e-6f32;
-        let mut err_eng = 1e-6f32;
         for i in 0..L {
             let echo_est = y_time[i + L].re * inv_n;
             error_out[i] = mic[i] - echo_est;
-            mic_eng += mic[i] * mic[i];
-            err_eng += error_out[i] * error_out[i];
         }
 
-        // 5. Weight Update (Frequency Domain)
-        let mut e_fft = [Complex32::default(); N];
+        // Error FFT, kept for the residual echo suppressor
+        let e_fft = &mut self.error_spectrum;
+        e_fft.fill(Complex32::default());
         for i in 0..L {
             e_fft[i + L] = Complex32::new(error_out[i], 0.0); // Constraint: Zero-padding first L
         }
-        self.fft_fwd.process(&mut e_fft);
-
-        // Simple Double-Talk Protection
-        if err_eng < mic_eng * 2.0 {
-            // Update Power Estimate
-            let current_x = &self.x_hist[self.hist_ptr];
-            for (f, item) in current_x.iter().enumerate().take(N) {
-                let p = item.norm_sqr();
-                self.power_est[f] = self.alpha * self.power_est[f] + (1.0 - self.alpha) * p;
+        self.fft_fwd.process(e_fft);
+
+        if !adapt {
+            return;
+        }
+
+        // 5. Weight Update (Frequency Domain)
+        let e_fft = &self.error_spectrum;
+
+        // Update Power Estimate
+        let current_x = &self.x_hist[self.hist_ptr];
+        for (f, item) in current_x.iter().enumerate().take(N) {
+            let p = item.norm_sqr();
+            self.power_est[f] = self.alpha * self.power_est[f] + (1.0 - self.alpha) * p;
+        }
+
+        for k in 0..K {
+            let x_idx = (self.hist_ptr + k) % K;
+            let xk = &self.x_hist[x_idx];
+            let wk = &mut self.w[k];
+
+            for f in 0..N {
+                let step = self.mu / (self.power_est[f] + self.eps);
+                let grad = e_fft[f] * xk[f].conj();
+                wk[f] = (wk[f] * self.leaky) + (grad * step);
             }
 
-            for k in 0..K {
-                let x_idx = (self.hist_ptr + k) % K;
-                let xk = &self.x_hist[x_idx];
-                let wk = &mut self.w[k];
-
-                for f in 0..N {
-                    let step = self.mu / (self.power_est[f] + self.eps);
-                    let grad = e_fft[f] * xk[f].conj();
-                    wk[f] = (wk[f] * self.leaky) + (grad * step);
-                }
-
-                // 6. Weight Projection (Constraint)
-                // Force linear convolution by zeroing the last L samples in time domain
-                let mut w_time = *wk;
-                self.fft_inv.process(&mut w_time);
-
-                let mut w_constrained = [Complex32::default(); N];
-                for i in 0..L {
-                    w_constrained[i] = Complex32::new(w_time[i].re * inv_n, 0.0);
-                }
-                self.fft_fwd.process(&mut w_constrained);
-                wk.copy_from_slice(&w_constrained);
+            // 6. Weight Projection (Constraint)
+            // Force linear convolution by zeroing the last L samples in time domain
+            let mut w_time = *wk;
+            self.fft_inv.process(&mut w_time);
+
+            let mut w_constrained = [Complex32::default(); N];
+            for i in 0..L {
+                w_constrained[i] = Complex32::new(w_time[i].re * inv_n, 0.0);
             }
+            self.fft_fwd.process(&mut w_constrained);
+            wk.copy_from_slice(&w_constrained);
         }
     }
 }
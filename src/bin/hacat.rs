@@ -1,73 +1,396 @@
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
 use clap::{Parser, Subcommand};
-use hachimi_cat::AudioServices;
-use iroh::{Endpoint, EndpointId};
+use hachimi_cat::{
+    ALPN, ALPNS, AudioServices, AudioServicesConfig,
+    codec::FrameDuration,
+    congestion::BitrateBounds,
+    impairment::{ImpairedTransport, Impairment},
+    transport::Role,
+};
+use hacore::{
+    AudioPipeline,
+    devices::{self, DeviceId, DeviceSelection, SupportedStreamConfigRange},
+    file_audio_engine::{FileEngineConfig, FileSource},
+};
+#[cfg(feature = "hachimi")]
+use hacore::{EchoCanceller, EchoConfig, SuppressionLevel};
+use iroh::{
+    Endpoint, EndpointId,
+    endpoint::{ConnectOptions, Connection},
+};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
 
 #[derive(Parser)]
 #[command(name = "hacat")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Microphone to use, as listed by `hacat devices`
+    #[arg(long, global = true)]
+    input_device: Option<DeviceId>,
+    /// Speaker to use, as listed by `hacat devices`
+    #[arg(long, global = true)]
+    output_device: Option<DeviceId>,
+    /// Send this WAV file instead of recording, no sound card needed
+    #[arg(long, global = true)]
+    input_wav: Option<PathBuf>,
+    /// Record what the peers say to this WAV file instead of playing it
+    #[arg(long, global = true)]
+    output_wav: Option<PathBuf>,
+    /// Drop that share of the received packets, e.g. `10%`
+    #[arg(long, global = true, value_parser = parse_percent)]
+    simulate_loss: Option<f64>,
+    /// Delay received packets by up to that much more, e.g. `40ms`
+    #[arg(long, global = true, value_parser = parse_millis)]
+    jitter: Option<Duration>,
+    /// Seed of the simulated network, to replay the same losses
+    #[arg(long, global = true, default_value_t = 0)]
+    seed: u64,
+    /// Lowest bitrate congestion control may pick, e.g. `6kbps`
+    #[arg(long, global = true, value_parser = parse_kbps)]
+    min_bitrate: Option<u32>,
+    /// Highest bitrate congestion control may pick, e.g. `64kbps`
+    #[arg(long, global = true, value_parser = parse_kbps)]
+    max_bitrate: Option<u32>,
+    /// Audio per packet: `10ms` on a LAN, up to `60ms` on a thin link
+    #[arg(long, global = true, value_parser = parse_frame_duration)]
+    frame_duration: Option<FrameDuration>,
+    /// Echo cancellation to use: `webrtc`, or the pure-Rust `hachimi` and
+    /// `hachimi-pbfdaf`
+    #[arg(long, global = true, value_parser = parse_pipeline)]
+    pipeline: Option<AudioPipeline>,
+    /// Residual echo suppression of the hachimi pipelines: `off`, `low`,
+    /// `moderate` or `high`
+    #[cfg(feature = "hachimi")]
+    #[arg(long, global = true, value_parser = parse_suppression)]
+    echo_suppression: Option<SuppressionLevel>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Listen,
-    Call { id: String },
+    Call {
+        id: String,
+    },
+    /// List audio hosts, their devices and supported configs
+    Devices,
 }
 
-const ALPN: &[u8] = b"hacat/opus/1";
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
+    if let Commands::Devices = cli.command {
+        print_devices();
+        return Ok(());
+    }
+
     let mdns = iroh::discovery::mdns::MdnsDiscovery::builder();
     let dht = iroh::discovery::pkarr::dht::DhtDiscovery::builder();
 
-    let alpns = vec![ALPN.to_vec()];
+    let alpns = ALPNS.iter().map(|alpn| alpn.to_vec()).collect();
+
+    let pipeline = pipeline(&cli);
+    // either file option takes the sound card out of the loop entirely
+    let file_engine =
+        (cli.input_wav.is_some() || cli.output_wav.is_some()).then(|| FileEngineConfig {
+            source: cli.input_wav.map_or(FileSource::Silence, FileSource::Wav),
+            sink: cli.output_wav,
+            realtime: true,
+        });
+    let mut audio_services = AudioServices::with_config(AudioServicesConfig {
+        devices: DeviceSelection {
+            input: cli.input_device,
+            output: cli.output_device,
+        },
+        file_engine,
+        bitrate: BitrateBounds {
+            min: cli.min_bitrate.unwrap_or(BitrateBounds::default().min),
+            max: cli.max_bitrate.unwrap_or(BitrateBounds::default().max),
+        },
+        frame_duration: cli.frame_duration.unwrap_or_default(),
+        pipeline,
+    })?;
+
+    let impairment = (cli.simulate_loss.is_some() || cli.jitter.is_some()).then(|| Impairment {
+        loss: cli.simulate_loss.unwrap_or_default(),
+        jitter: cli.jitter.unwrap_or_default(),
+        seed: cli.seed,
+        ..Default::default()
+    });
+
+    let mut events = audio_services.subscribe();
+    tokio::task::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => println!("{:?}", event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
 
-    let mut audio_services = AudioServices::new()?;
+    let endpoint = Endpoint::builder()
+        .discovery(mdns)
+        .discovery(dht)
+        .alpns(alpns)
+        .bind()
+        .await?;
+
+    let mut keys = spawn_keys();
+    println!("{KEYS_HELP}");
 
     match cli.command {
         Commands::Listen => {
-            let endpoint = Endpoint::builder()
-                .discovery(mdns)
-                .discovery(dht)
-                .alpns(alpns)
-                .bind()
-                .await?;
             let local_id = endpoint.id();
             println!("local id: {}", local_id);
 
-            while let Some(incoming) = endpoint.accept().await {
-                let connecting = incoming.accept()?;
-                let connection = connecting.await?;
+            loop {
+                tokio::select! {
+                    incoming = endpoint.accept() => {
+                        let Some(incoming) = incoming else {
+                            break;
+                        };
+                        let connecting = incoming.accept()?;
+                        let connection = connecting.await?;
 
-                audio_services.add_connection(connection)?;
+                        add_call(&mut audio_services, connection, Role::Callee, &impairment)?;
+                    }
+                    Some(line) = keys.recv() => on_key(&mut audio_services, &line),
+                    _ = tokio::signal::ctrl_c() => break,
+                }
             }
         }
         Commands::Call { id } => {
-            let endpoint = Endpoint::builder()
-                .discovery(mdns)
-                .discovery(dht)
-                .alpns(alpns)
-                .bind()
+            // older versions stay reachable, and a bridge only speaks version 1
+            let older = ALPNS[1..].iter().map(|alpn| alpn.to_vec()).collect();
+            let connection = endpoint
+                .connect_with_opts(
+                    EndpointId::from_str(&id)?,
+                    ALPN,
+                    ConnectOptions::new().with_additional_alpns(older),
+                )
+                .await?
                 .await?;
-            let connection = endpoint.connect(EndpointId::from_str(&id)?, ALPN).await?;
 
-            audio_services.add_connection(connection)?;
+            add_call(
+                &mut audio_services,
+                connection.clone(),
+                Role::Caller,
+                &impairment,
+            )?;
+
+            let closed = connection.closed();
+            tokio::pin!(closed);
+            loop {
+                tokio::select! {
+                    _ = &mut closed => {
+                        println!("Call ended by peer.");
+                        break;
+                    }
+                    Some(line) = keys.recv() => on_key(&mut audio_services, &line),
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
         }
+        Commands::Devices => unreachable!("listed before starting audio"),
     }
 
-    tokio::signal::ctrl_c().await?;
-
-    // for service in running_services {
-    // TODO: safety close connection
-    // service.connection.close()
-    // }
-
     println!("Shutting down.");
+    audio_services.shutdown().await?;
+    endpoint.close().await;
     Ok(())
 }
+
+fn add_call(
+    audio_services: &mut AudioServices,
+    connection: Connection,
+    role: Role,
+    impairment: &Option<Impairment>,
+) -> anyhow::Result<()> {
+    match impairment {
+        Some(impairment) => audio_services
+            .add_connection(ImpairedTransport::new(connection, impairment.clone()), role),
+        None => audio_services.add_connection(connection, role),
+    }
+}
+
+/// Terminals report no key releases, so push-to-talk toggles on Enter.
+const KEYS_HELP: &str = "keys: m + Enter mutes, d + Enter deafens, \
+    p + Enter switches push-to-talk, Enter alone then starts and stops talking";
+
+/// Lines typed on the terminal while calls run.
+fn spawn_keys() -> mpsc::UnboundedReceiver<String> {
+    let (keys_prod, keys) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            if keys_prod.send(line).is_err() {
+                return;
+            }
+        }
+    });
+    keys
+}
+
+fn on_key(audio_services: &mut AudioServices, line: &str) {
+    match line.trim() {
+        "m" => {
+            let muted = !audio_services.is_mic_muted();
+            audio_services.set_mic_muted(muted);
+            println!("microphone {}", if muted { "muted" } else { "on" });
+        }
+        "d" => {
+            let deafened = !audio_services.is_deafened();
+            audio_services.set_deafened(deafened);
+            println!("speaker {}", if deafened { "deafened" } else { "on" });
+        }
+        "p" => {
+            let push_to_talk = !audio_services.is_push_to_talk();
+            audio_services.set_push_to_talk(push_to_talk);
+            audio_services.set_talking(false);
+            println!("push-to-talk {}", if push_to_talk { "on" } else { "off" });
+        }
+        "" if audio_services.is_push_to_talk() => {
+            let talking = !audio_services.is_talking();
+            audio_services.set_talking(talking);
+            println!("{}", if talking { "talking" } else { "not talking" });
+        }
+        "" => {}
+        _ => println!("{KEYS_HELP}"),
+    }
+}
+
+fn parse_percent(value: &str) -> Result<f64, String> {
+    let percent: f64 = value
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("`{value}` is not a percentage"))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("`{value}` is not between 0% and 100%"));
+    }
+    Ok(percent / 100.0)
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    let millis: u64 = value
+        .trim_end_matches("ms")
+        .parse()
+        .map_err(|_| format!("`{value}` is not a duration in milliseconds"))?;
+    Ok(Duration::from_millis(millis))
+}
+
+fn parse_kbps(value: &str) -> Result<u32, String> {
+    let kbps: u32 = value
+        .trim_end_matches("kbps")
+        .parse()
+        .map_err(|_| format!("`{value}` is not a bitrate in kbps"))?;
+    if !(6..=510).contains(&kbps) {
+        return Err(format!("`{value}` is not between 6kbps and 510kbps"));
+    }
+    Ok(kbps * 1000)
+}
+
+fn parse_frame_duration(value: &str) -> Result<FrameDuration, String> {
+    let millis = parse_millis(value)?.as_millis() as u64;
+    FrameDuration::from_millis(millis)
+        .ok_or_else(|| format!("`{value}` is not one of 10ms, 20ms, 40ms or 60ms"))
+}
+
+/// The selected pipeline, with the selected suppression if it is a hachimi one.
+fn pipeline(cli: &Cli) -> AudioPipeline {
+    let pipeline = cli.pipeline.unwrap_or_default();
+    #[cfg(feature = "hachimi")]
+    if let (AudioPipeline::Hachimi(config), Some(suppression)) = (pipeline, cli.echo_suppression) {
+        return AudioPipeline::Hachimi(EchoConfig {
+            suppression,
+            ..config
+        });
+    }
+    pipeline
+}
+
+fn parse_pipeline(value: &str) -> Result<AudioPipeline, String> {
+    match value {
+        #[cfg(feature = "webrtc")]
+        "webrtc" => Ok(AudioPipeline::WebRtc),
+        #[cfg(feature = "hachimi")]
+        "hachimi" => Ok(AudioPipeline::Hachimi(EchoConfig::default())),
+        #[cfg(feature = "hachimi")]
+        "hachimi-pbfdaf" => Ok(AudioPipeline::Hachimi(EchoConfig {
+            canceller: EchoCanceller::Pbfdaf,
+            ..Default::default()
+        })),
+        _ => Err(format!(
+            "`{value}` is not one of the pipelines built in: {}",
+            PIPELINES.join(", ")
+        )),
+    }
+}
+
+/// Values `--pipeline` accepts in this build.
+const PIPELINES: &[&str] = &[
+    #[cfg(feature = "webrtc")]
+    "webrtc",
+    #[cfg(feature = "hachimi")]
+    "hachimi",
+    #[cfg(feature = "hachimi")]
+    "hachimi-pbfdaf",
+];
+
+#[cfg(feature = "hachimi")]
+fn parse_suppression(value: &str) -> Result<SuppressionLevel, String> {
+    match value {
+        "off" => Ok(SuppressionLevel::Off),
+        "low" => Ok(SuppressionLevel::Low),
+        "moderate" => Ok(SuppressionLevel::Moderate),
+        "high" => Ok(SuppressionLevel::High),
+        _ => Err(format!(
+            "`{value}` is not one of off, low, moderate or high"
+        )),
+    }
+}
+
+fn print_devices() {
+    for host in devices::list_hosts() {
+        println!("{}", host.id);
+        for device in host.devices {
+            let mut defaults = Vec::new();
+            if device.is_default_input {
+                defaults.push("default input");
+            }
+            if device.is_default_output {
+                defaults.push("default output");
+            }
+            if defaults.is_empty() {
+                println!("  {} \"{}\"", device.id, device.name);
+            } else {
+                println!(
+                    "  {} \"{}\" ({})",
+                    device.id,
+                    device.name,
+                    defaults.join(", ")
+                );
+            }
+            for config in &device.input_configs {
+                println!("    input:  {}", format_config(config));
+            }
+            for config in &device.output_configs {
+                println!("    output: {}", format_config(config));
+            }
+        }
+    }
+}
+
+fn format_config(config: &SupportedStreamConfigRange) -> String {
+    format!(
+        "{} ch, {}-{} Hz, {}",
+        config.channels(),
+        config.min_sample_rate(),
+        config.max_sample_rate(),
+        config.sample_format()
+    )
+}
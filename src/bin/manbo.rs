@@ -1,4 +1,101 @@
+use std::sync::Arc;
+
+use clap::{Parser, ValueEnum};
+use hachimi_cat::{ALPN_V1, bridge::ConferenceBridge, sfu::ForwardingUnit};
+use iroh::{Endpoint, endpoint::Accepting};
+
+#[derive(Parser)]
+#[command(name = "manbo")]
+struct Cli {
+    /// `mix` decodes everyone and sends each participant a mix of the others,
+    /// `sfu` relays the Opus packets as they are and lets clients mix.
+    #[arg(long, value_enum, default_value_t = Mode::Mix)]
+    mode: Mode,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mode {
+    Mix,
+    Sfu,
+}
+
+enum Bridge {
+    Mix(ConferenceBridge),
+    Sfu(ForwardingUnit),
+}
+
+impl Bridge {
+    /// Completes the handshake of one participant and lets it in. A peer
+    /// that fails either is dropped on its own, the others stay.
+    async fn join(&self, connecting: Accepting) {
+        let connection = match connecting.await {
+            Ok(connection) => connection,
+            Err(err) => {
+                eprintln!("participant failed to connect: {err}");
+                return;
+            }
+        };
+        let remote_id = connection.remote_id();
+        println!("participant joined: {}", remote_id);
+        match self {
+            Bridge::Mix(bridge) => {
+                if let Err(err) = bridge.add_connection(connection) {
+                    eprintln!("participant {} dropped: {err}", remote_id);
+                }
+            }
+            Bridge::Sfu(sfu) => sfu.add_connection(connection).await,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let mdns = iroh::discovery::mdns::MdnsDiscovery::builder();
+    let dht = iroh::discovery::pkarr::dht::DhtDiscovery::builder();
+
+    // neither the bridge nor the forwarding unit has a control stream
+    let alpns = vec![ALPN_V1.to_vec()];
+
+    let bridge = Arc::new(match cli.mode {
+        Mode::Mix => Bridge::Mix(ConferenceBridge::new()?),
+        Mode::Sfu => Bridge::Sfu(ForwardingUnit::new()),
+    });
+
+    let endpoint = Endpoint::builder()
+        .discovery(mdns)
+        .discovery(dht)
+        .alpns(alpns)
+        .bind()
+        .await?;
+    let local_id = endpoint.id();
+    println!("bridge id: {}", local_id);
+
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else {
+                    break;
+                };
+                let connecting = match incoming.accept() {
+                    Ok(connecting) => connecting,
+                    Err(err) => {
+                        eprintln!("participant refused: {err}");
+                        continue;
+                    }
+                };
+                // a slow handshake must not hold up the next participant
+                let bridge = bridge.clone();
+                tokio::spawn(async move { bridge.join(connecting).await });
+            }
+            _ = tokio::signal::ctrl_c() => {
+                break;
+            }
+        }
+    }
+
+    endpoint.close().await;
+    println!("Shutting down.");
     Ok(())
 }
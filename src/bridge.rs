@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use hacore::FRAME20MS;
+use iroh::{EndpointId, endpoint::Connection};
+use tokio::sync::mpsc;
+
+use crate::{
+    RECEIVER_REPORT_INTERVAL, build_opus_encoder,
+    congestion::{
+        BitrateBounds, CongestionController, EncoderSettings, LinkFeedback, START_BITRATE,
+    },
+    decode_command,
+    error::Error,
+    jitter::JitterBuffer,
+    mixer::mix_minus,
+    rtp::{OPUS_PAYLOAD_TYPE, ReceiverReport, RtpPacket, RtpSequencer, is_rtcp},
+    transport::Transport,
+};
+
+/// Mix-minus conference bridge: every participant is decoded, and each one
+/// gets back the mix of all the others, re-encoded with its own encoder.
+pub struct ConferenceBridge {
+    participant_prod: mpsc::UnboundedSender<Participant>,
+    pub mixer_thread: std::thread::JoinHandle<()>,
+}
+
+struct Participant {
+    endpoint_id: EndpointId,
+    connection: Connection,
+    reciver_thread: tokio::task::JoinHandle<()>,
+    packets: mpsc::Receiver<Incoming>,
+    jitter: JitterBuffer,
+    decoder: opus::Decoder,
+    encoder: opus::Encoder,
+    congestion: CongestionController,
+    sequencer: RtpSequencer,
+    frame: [f32; FRAME20MS],
+    last_report: Instant,
+}
+
+enum Incoming {
+    Packet(RtpPacket, Instant),
+    /// What the participant reports about the mix it gets from us.
+    Report(ReceiverReport),
+}
+
+impl Drop for Participant {
+    fn drop(&mut self) {
+        self.reciver_thread.abort();
+    }
+}
+
+impl ConferenceBridge {
+    pub fn new() -> anyhow::Result<Self> {
+        let (participant_prod, participant_cons) = mpsc::unbounded_channel();
+        let mixer_thread = std::thread::Builder::new()
+            .name("Bridge Mixer Thread".to_owned())
+            .spawn(|| {
+                if bridge_mix(participant_cons).is_err() {
+                    // cancellation
+                }
+            })?;
+        Ok(ConferenceBridge {
+            participant_prod,
+            mixer_thread,
+        })
+    }
+
+    pub fn add_connection(&self, connection: Connection) -> anyhow::Result<()> {
+        let conn_for_recv = connection.clone();
+        let (packet_prod, packets) = mpsc::channel(16);
+
+        let reciver_thread = tokio::task::spawn(async move {
+            while let Ok(datagram) = conn_for_recv.read_datagram().await {
+                let incoming = if is_rtcp(&datagram) {
+                    let Ok(report) = ReceiverReport::parse(&datagram) else {
+                        continue;
+                    };
+                    Incoming::Report(report)
+                } else {
+                    let Ok(packet) = RtpPacket::parse(datagram) else {
+                        continue;
+                    };
+                    if packet.header.payload_type != OPUS_PAYLOAD_TYPE {
+                        continue;
+                    }
+                    Incoming::Packet(packet, Instant::now())
+                };
+                if packet_prod.send(incoming).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let participant = Participant {
+            endpoint_id: connection.remote_id(),
+            connection,
+            reciver_thread,
+            packets,
+            jitter: JitterBuffer::new(FRAME20MS as u32),
+            decoder: opus::Decoder::new(48000, opus::Channels::Mono)?,
+            encoder: build_opus_encoder(&EncoderSettings::new(START_BITRATE, 0.0))?,
+            congestion: CongestionController::new(BitrateBounds::default()),
+            sequencer: RtpSequencer::new(rand::random(), OPUS_PAYLOAD_TYPE),
+            frame: [0f32; FRAME20MS],
+            last_report: Instant::now(),
+        };
+        self.participant_prod
+            .send(participant)
+            .map_err(|_| Error::BridgeClosed)?;
+        Ok(())
+    }
+}
+
+impl Participant {
+    /// Moves arrived packets into the jitter buffer and decodes one frame.
+    /// Returns `false` once the participant has left.
+    fn pull(&mut self) -> bool {
+        loop {
+            match self.packets.try_recv() {
+                Ok(Incoming::Packet(packet, arrival)) => self.jitter.push(packet, arrival),
+                Ok(Incoming::Report(report)) => {
+                    if let Some(loss) = report.worst_loss() {
+                        let bitrate = self.congestion.on_feedback(LinkFeedback {
+                            loss,
+                            path: self.connection.path_stats(),
+                        });
+                        let settings = EncoderSettings::new(bitrate, self.congestion.loss());
+                        let _ = settings.apply(&mut self.encoder);
+                    }
+                }
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => return false,
+            }
+        }
+
+        let command = self.jitter.pop();
+        let decoded = decode_command(&mut self.decoder, &command, &mut self.frame);
+        if decoded.ok() != Some(FRAME20MS) {
+            self.frame.fill(0.0);
+        }
+        true
+    }
+
+    /// Encodes `mix` and sends it. Returns `false` once the participant has left.
+    fn push(&mut self, mix: &[f32], output: &mut [u8]) -> bool {
+        let Ok(encode_size) = self.encoder.encode_float(mix, output) else {
+            return true;
+        };
+        let packet = self.sequencer.packetize(
+            Bytes::copy_from_slice(&output[..encode_size]),
+            FRAME20MS as u32,
+        );
+        if self.connection.send_datagram(packet.to_bytes()).is_err() {
+            return false;
+        }
+
+        if self.last_report.elapsed() >= RECEIVER_REPORT_INTERVAL {
+            self.last_report = Instant::now();
+            if let Some(block) = self.jitter.report() {
+                let report = ReceiverReport {
+                    ssrc: self.sequencer.ssrc(),
+                    blocks: vec![block],
+                };
+                return self.connection.send_datagram(report.to_bytes()).is_ok();
+            }
+        }
+        true
+    }
+}
+
+fn bridge_mix(mut participant_cons: mpsc::UnboundedReceiver<Participant>) -> anyhow::Result<()> {
+    let mut participants: HashMap<EndpointId, Participant> = HashMap::new();
+
+    let mut total = [0f32; FRAME20MS];
+    let mut mix = [0f32; FRAME20MS];
+    let mut output = [0u8; 4096];
+
+    let tick = Duration::from_millis(20);
+    let mut deadline = Instant::now();
+
+    loop {
+        loop {
+            match participant_cons.try_recv() {
+                Ok(participant) => {
+                    // a reconnecting endpoint replaces its stale entry
+                    participants.insert(participant.endpoint_id, participant);
+                }
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        participants.retain(|_, participant| participant.pull());
+
+        total.fill(0.0);
+        for participant in participants.values() {
+            for (sum, &sample) in total.iter_mut().zip(participant.frame.iter()) {
+                *sum += sample;
+            }
+        }
+
+        participants.retain(|_, participant| {
+            mix_minus(&total, &participant.frame, &mut mix);
+            participant.push(&mix, &mut output)
+        });
+
+        // the bridge has no sound card, so it keeps its own 20 ms clock
+        deadline += tick;
+        let now = Instant::now();
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        } else {
+            deadline = now;
+        }
+    }
+}
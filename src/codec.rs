@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use hacore::SAMPLE_RATE;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+/// Audio covered by one Opus packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum FrameDuration {
+    Ms10,
+    #[default]
+    Ms20,
+    Ms40,
+    Ms60,
+}
+
+impl FrameDuration {
+    pub const ALL: [FrameDuration; 4] = [
+        FrameDuration::Ms10,
+        FrameDuration::Ms20,
+        FrameDuration::Ms40,
+        FrameDuration::Ms60,
+    ];
+
+    pub fn from_millis(millis: u64) -> Option<Self> {
+        match millis {
+            10 => Some(FrameDuration::Ms10),
+            20 => Some(FrameDuration::Ms20),
+            40 => Some(FrameDuration::Ms40),
+            60 => Some(FrameDuration::Ms60),
+            _ => None,
+        }
+    }
+
+    pub fn millis(self) -> u64 {
+        match self {
+            FrameDuration::Ms10 => 10,
+            FrameDuration::Ms20 => 20,
+            FrameDuration::Ms40 => 40,
+            FrameDuration::Ms60 => 60,
+        }
+    }
+
+    pub fn duration(self) -> Duration {
+        Duration::from_millis(self.millis())
+    }
+
+    /// Samples per frame at `SAMPLE_RATE`, which is also the RTP clock rate.
+    pub fn samples(self) -> usize {
+        SAMPLE_RATE as usize * self.millis() as usize / 1000
+    }
+}
+
+/// Level of `frame` for a comfort noise packet, in -dBov (RFC 3389): 0 is
+/// full scale, 127 is the quietest.
+pub fn noise_level(frame: &[f32]) -> u8 {
+    let rms = (frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32).sqrt();
+    (-20.0 * rms.max(1e-7).log10()).clamp(0.0, 127.0) as u8
+}
+
+/// Fills the gaps a sender leaves on purpose with noise at the level it
+/// last signalled, so that they do not sound like a dropped call.
+pub struct ComfortNoise {
+    rng: SmallRng,
+    /// One-pole low-pass state; room noise is rarely white.
+    state: f32,
+}
+
+impl Default for ComfortNoise {
+    fn default() -> Self {
+        Self {
+            rng: SmallRng::from_os_rng(),
+            state: 0.0,
+        }
+    }
+}
+
+impl ComfortNoise {
+    pub fn fill(&mut self, level: u8, frame: &mut [f32]) {
+        let rms = 10f32.powf(-(level as f32) / 20.0);
+        // uniform noise has an RMS of 1/√3, and the low-pass keeps a third of its power
+        let gain = rms * 3.0;
+        for sample in frame.iter_mut() {
+            let white: f32 = self.rng.random_range(-1.0..1.0);
+            self.state = 0.5 * self.state + 0.5 * white;
+            *sample = self.state * gain;
+        }
+    }
+}
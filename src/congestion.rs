@@ -0,0 +1,302 @@
+use std::{collections::VecDeque, time::Duration};
+
+use crate::transport::PathStats;
+
+/// Bitrate a call starts at, before any receiver report has arrived.
+pub const START_BITRATE: u32 = 32_000;
+/// Loss above which the bitrate is cut in proportion to it.
+const HIGH_LOSS: f32 = 0.1;
+/// Loss below which the bitrate may grow.
+const LOW_LOSS: f32 = 0.02;
+/// Round-trip time above the path's minimum that means queues are building.
+const QUEUING_DELAY: Duration = Duration::from_millis(80);
+/// Growth of the round-trip time between two reports that counts as rising.
+const DELAY_GRADIENT: Duration = Duration::from_millis(10);
+/// Reports the minimum round-trip time is taken over.
+const MIN_RTT_WINDOW: usize = 30;
+const DECREASE_FACTOR: f32 = 0.85;
+const INCREASE_FACTOR: f32 = 1.08;
+/// Added on every increase so that low bitrates recover quickly too.
+const INCREASE_STEP: f32 = 1_000.0;
+/// Reported loss, in percent, from which the encoder adds in-band FEC.
+pub const FEC_LOSS_THRESHOLD: u8 = 1;
+
+/// Range the encoder bitrate is kept in, in bits per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitrateBounds {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Default for BitrateBounds {
+    fn default() -> Self {
+        Self {
+            min: 6_000,
+            max: 64_000,
+        }
+    }
+}
+
+impl BitrateBounds {
+    pub fn clamp(&self, bitrate: u32) -> u32 {
+        bitrate.clamp(self.min, self.max.max(self.min))
+    }
+}
+
+/// What a receiver report, together with the transport, tells about the
+/// path to one peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkFeedback {
+    /// Share of our packets the peer lost since its previous report.
+    pub loss: f32,
+    pub path: PathStats,
+}
+
+/// Estimates the bitrate the path to one peer can carry, once per receiver
+/// report.
+///
+/// Loss above `HIGH_LOSS` cuts the bitrate in proportion to it. A rising
+/// round-trip time while queues are building, or a back-off of the
+/// transport's own congestion control, cuts it by a fixed factor. Otherwise
+/// it grows while the path stays clean.
+#[derive(Debug, Clone)]
+pub struct CongestionController {
+    bounds: BitrateBounds,
+    bitrate: f32,
+    loss: f32,
+    rtts: VecDeque<Duration>,
+    last_path: Option<PathStats>,
+}
+
+impl CongestionController {
+    pub fn new(bounds: BitrateBounds) -> Self {
+        Self {
+            bounds,
+            bitrate: bounds.clamp(START_BITRATE) as f32,
+            loss: 0.0,
+            rtts: VecDeque::with_capacity(MIN_RTT_WINDOW),
+            last_path: None,
+        }
+    }
+
+    /// Lowers the upper bound to `max`, e.g. what the peer accepts.
+    pub fn limit(&mut self, max: u32) {
+        self.bounds.max = self.bounds.max.min(max).max(self.bounds.min);
+        self.bitrate = self.bitrate.min(self.bounds.max as f32);
+    }
+
+    /// Current estimate, in bits per second.
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate as u32
+    }
+
+    /// Loss seen in the latest feedback, between 0.0 and 1.0.
+    pub fn loss(&self) -> f32 {
+        self.loss
+    }
+
+    /// Updates the estimate and returns it.
+    pub fn on_feedback(&mut self, feedback: LinkFeedback) -> u32 {
+        let path = feedback.path;
+        let previous = self.last_path.replace(path);
+
+        let mut path_loss = 0.0;
+        let mut congested = false;
+        let mut rising = false;
+        if let Some(previous) = previous {
+            // datagrams are acknowledged, so the transport sees their loss as well
+            let sent = path.sent_packets.saturating_sub(previous.sent_packets);
+            let lost = path.lost_packets.saturating_sub(previous.lost_packets);
+            if sent > 0 {
+                path_loss = lost as f32 / sent as f32;
+            }
+            congested = path.congestion_events > previous.congestion_events;
+            rising = path.rtt > previous.rtt + DELAY_GRADIENT;
+        }
+        self.loss = feedback.loss.max(path_loss).clamp(0.0, 1.0);
+
+        if self.rtts.len() == MIN_RTT_WINDOW {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(path.rtt);
+        let min_rtt = self.rtts.iter().min().copied().unwrap_or(path.rtt);
+        let queuing = path.rtt.saturating_sub(min_rtt) > QUEUING_DELAY;
+
+        if self.loss > HIGH_LOSS {
+            self.bitrate *= 1.0 - 0.5 * self.loss;
+        } else if congested || (queuing && rising) {
+            self.bitrate *= DECREASE_FACTOR;
+        } else if self.loss < LOW_LOSS && !queuing {
+            self.bitrate = self.bitrate * INCREASE_FACTOR + INCREASE_STEP;
+        }
+        self.bitrate = self
+            .bitrate
+            .clamp(self.bounds.min as f32, self.bounds.max as f32);
+        self.bitrate()
+    }
+}
+
+/// Opus settings for a target bitrate and the loss expected on the way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderSettings {
+    pub bitrate: u32,
+    /// Narrower audio at low bitrates keeps speech intelligible instead of
+    /// spreading too few bits over the whole band.
+    pub max_bandwidth: opus::Bandwidth,
+    pub complexity: i32,
+    pub packet_loss_percent: u8,
+}
+
+impl EncoderSettings {
+    pub fn new(bitrate: u32, loss: f32) -> Self {
+        let max_bandwidth = match bitrate {
+            ..12_000 => opus::Bandwidth::Narrowband,
+            12_000..16_000 => opus::Bandwidth::Mediumband,
+            16_000..24_000 => opus::Bandwidth::Wideband,
+            24_000..32_000 => opus::Bandwidth::Superwideband,
+            _ => opus::Bandwidth::Fullband,
+        };
+        // narrow bands are cheap to encode, so the best search is affordable
+        let complexity = if bitrate < 24_000 { 10 } else { 8 };
+        Self {
+            bitrate,
+            max_bandwidth,
+            complexity,
+            packet_loss_percent: (loss * 100.0).round() as u8,
+        }
+    }
+
+    pub fn apply(&self, encoder: &mut opus::Encoder) -> Result<(), opus::Error> {
+        encoder.set_bitrate(opus::Bitrate::Bits(self.bitrate as i32))?;
+        encoder.set_max_bandwidth(self.max_bandwidth)?;
+        encoder.set_complexity(self.complexity)?;
+        set_packet_loss(encoder, self.packet_loss_percent)
+    }
+}
+
+/// Tunes the encoder for the loss its receivers report. Opus only spends
+/// bits on in-band FEC when it expects packets to get lost.
+fn set_packet_loss(encoder: &mut opus::Encoder, percent: u8) -> Result<(), opus::Error> {
+    encoder.set_packet_loss_perc(percent.min(100) as i32)?;
+    encoder.set_inband_fec(percent >= FEC_LOSS_THRESHOLD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feedback(loss: f32, rtt_ms: u64) -> LinkFeedback {
+        LinkFeedback {
+            loss,
+            path: PathStats {
+                rtt: Duration::from_millis(rtt_ms),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn grows_on_a_clean_path_up_to_the_bound() {
+        let mut controller = CongestionController::new(BitrateBounds::default());
+        assert_eq!(controller.bitrate(), START_BITRATE);
+
+        let grown = controller.on_feedback(feedback(0.0, 50));
+        assert_eq!(
+            grown,
+            (START_BITRATE as f32 * INCREASE_FACTOR + INCREASE_STEP) as u32
+        );
+        for _ in 0..50 {
+            controller.on_feedback(feedback(0.0, 50));
+        }
+        assert_eq!(controller.bitrate(), BitrateBounds::default().max);
+    }
+
+    #[test]
+    fn high_loss_cuts_in_proportion_down_to_the_bound() {
+        let mut controller = CongestionController::new(BitrateBounds::default());
+        assert_eq!(
+            controller.on_feedback(feedback(0.3, 50)),
+            (START_BITRATE as f32 * 0.85) as u32
+        );
+        assert_eq!(controller.loss(), 0.3);
+
+        for _ in 0..50 {
+            controller.on_feedback(feedback(0.3, 50));
+        }
+        assert_eq!(controller.bitrate(), BitrateBounds::default().min);
+    }
+
+    #[test]
+    fn moderate_loss_holds_the_bitrate() {
+        let mut controller = CongestionController::new(BitrateBounds::default());
+        assert_eq!(controller.on_feedback(feedback(0.05, 50)), START_BITRATE);
+    }
+
+    #[test]
+    fn rising_round_trip_over_the_minimum_cuts_the_bitrate() {
+        let mut controller = CongestionController::new(BitrateBounds::default());
+        controller.on_feedback(feedback(0.0, 50));
+        let before = controller.bitrate();
+
+        // queues build up: 100 ms over the minimum and still rising
+        let cut = controller.on_feedback(feedback(0.0, 150));
+        assert_eq!(cut, (before as f32 * DECREASE_FACTOR) as u32);
+
+        // a steady queue neither cuts nor grows
+        assert_eq!(controller.on_feedback(feedback(0.0, 150)), cut);
+
+        // a rise within the usual jitter of the path does not cut
+        let mut controller = CongestionController::new(BitrateBounds::default());
+        controller.on_feedback(feedback(0.0, 50));
+        assert!(controller.on_feedback(feedback(0.0, 70)) > before);
+    }
+
+    #[test]
+    fn transport_loss_and_back_off_count() {
+        let mut controller = CongestionController::new(BitrateBounds::default());
+        let mut path = PathStats {
+            rtt: Duration::from_millis(50),
+            sent_packets: 100,
+            ..Default::default()
+        };
+        controller.on_feedback(LinkFeedback { loss: 0.0, path });
+        let before = controller.bitrate();
+
+        // the peer's report has not caught up with what the transport lost
+        path.sent_packets += 100;
+        path.lost_packets += 20;
+        controller.on_feedback(LinkFeedback { loss: 0.0, path });
+        assert_eq!(controller.loss(), 0.2);
+        assert_eq!(controller.bitrate(), (before as f32 * 0.9) as u32);
+
+        let before = controller.bitrate();
+        path.sent_packets += 100;
+        path.congestion_events += 1;
+        controller.on_feedback(LinkFeedback { loss: 0.0, path });
+        assert_eq!(
+            controller.bitrate(),
+            (before as f32 * DECREASE_FACTOR) as u32
+        );
+    }
+
+    #[test]
+    fn limit_lowers_the_bitrate_and_its_bound() {
+        let mut controller = CongestionController::new(BitrateBounds::default());
+        controller.limit(20_000);
+        assert_eq!(controller.bitrate(), 20_000);
+        for _ in 0..10 {
+            controller.on_feedback(feedback(0.0, 50));
+        }
+        assert_eq!(controller.bitrate(), 20_000);
+    }
+
+    #[test]
+    fn settings_narrow_the_band_at_low_bitrates() {
+        let low = EncoderSettings::new(8_000, 0.126);
+        assert_eq!(low.max_bandwidth, opus::Bandwidth::Narrowband);
+        assert_eq!(low.packet_loss_percent, 13);
+        let high = EncoderSettings::new(START_BITRATE, 0.0);
+        assert_eq!(high.max_bandwidth, opus::Bandwidth::Fullband);
+        assert_eq!(high.packet_loss_percent, 0);
+    }
+}
@@ -0,0 +1,603 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch},
+};
+
+use crate::{
+    codec::FrameDuration,
+    error::Error,
+    link::LinkStats,
+    transport::{ControlStream, Role, Transport},
+};
+
+/// Longest control message accepted, length prefix excluded.
+pub const MAX_CONTROL_MESSAGE: usize = 4096;
+/// How often a keepalive is sent to a peer that has said hello.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+/// A peer that has said hello and then stays silent for that long is gone.
+pub const CONTROL_TIMEOUT: Duration = Duration::from_secs(20);
+
+const HELLO: u8 = 0;
+const MUTE: u8 = 1;
+const STATS: u8 = 2;
+const BYE: u8 = 3;
+const KEEPALIVE: u8 = 4;
+
+/// Hello capability tags. Each capability is sent as tag, length, value, so
+/// a peer skips the ones it does not know.
+const CAPABILITY_MAX_BITRATE: u8 = 0;
+const CAPABILITY_FRAME_DURATIONS: u8 = 1;
+const CAPABILITY_PREFERRED_FRAME_DURATION: u8 = 2;
+const CAPABILITY_CHANNELS: u8 = 3;
+const CAPABILITY_FEC: u8 = 4;
+const CAPABILITY_DTX: u8 = 5;
+
+/// Highest bitrate Opus encodes at, assumed for a peer that names none.
+pub const OPUS_MAX_BITRATE: u32 = 510_000;
+
+/// What a peer says about itself when the control stream opens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub capabilities: Capabilities,
+}
+
+/// What a peer can send and receive. A capability missing from a hello
+/// takes the value version 1 of the protocol implied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub frame_durations: Vec<FrameDuration>,
+    /// The one among `frame_durations` the peer would rather use.
+    pub preferred_frame_duration: FrameDuration,
+    /// Highest channel count the peer encodes and decodes.
+    pub channels: u8,
+    /// Highest bitrate the peer sends or wants to receive, in bits per second.
+    pub max_bitrate: u32,
+    /// The peer decodes in-band FEC, so sending it is worth the bits.
+    pub fec: bool,
+    /// The peer copes with gaps in a stream that are not losses.
+    pub dtx: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            frame_durations: vec![FrameDuration::Ms20],
+            preferred_frame_duration: FrameDuration::Ms20,
+            channels: 1,
+            max_bitrate: OPUS_MAX_BITRATE,
+            fec: true,
+            dtx: false,
+        }
+    }
+}
+
+/// What both sides of a call have agreed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallParameters {
+    pub version: u8,
+    pub frame_duration: FrameDuration,
+    pub channels: u8,
+    pub max_bitrate: u32,
+    pub fec: bool,
+    pub dtx: bool,
+}
+
+impl CallParameters {
+    /// What version 1 fixed, for peers that say no hello.
+    pub fn version1() -> Self {
+        Self {
+            version: 1,
+            frame_duration: FrameDuration::Ms20,
+            channels: 1,
+            max_bitrate: OPUS_MAX_BITRATE,
+            fec: true,
+            dtx: false,
+        }
+    }
+}
+
+impl Hello {
+    fn put(&self, buf: &mut BytesMut) {
+        let capabilities = &self.capabilities;
+        buf.put_u8(self.version);
+
+        buf.put_u8(CAPABILITY_MAX_BITRATE);
+        buf.put_u8(4);
+        buf.put_u32(capabilities.max_bitrate);
+
+        buf.put_u8(CAPABILITY_FRAME_DURATIONS);
+        buf.put_u8(capabilities.frame_durations.len() as u8);
+        for duration in &capabilities.frame_durations {
+            buf.put_u8(duration.millis() as u8);
+        }
+
+        buf.put_u8(CAPABILITY_PREFERRED_FRAME_DURATION);
+        buf.put_u8(1);
+        buf.put_u8(capabilities.preferred_frame_duration.millis() as u8);
+
+        for (tag, value) in [
+            (CAPABILITY_CHANNELS, capabilities.channels),
+            (CAPABILITY_FEC, capabilities.fec as u8),
+            (CAPABILITY_DTX, capabilities.dtx as u8),
+        ] {
+            buf.put_u8(tag);
+            buf.put_u8(1);
+            buf.put_u8(value);
+        }
+    }
+
+    fn parse(mut body: &[u8]) -> Result<Self, Error> {
+        if body.is_empty() {
+            return Err(Error::ControlMessageTooShort);
+        }
+        let version = body.get_u8();
+        let mut capabilities = Capabilities::default();
+        while body.len() >= 2 {
+            let tag = body.get_u8();
+            let len = body.get_u8() as usize;
+            if body.len() < len {
+                return Err(Error::ControlMessageTooShort);
+            }
+            let (mut value, rest) = body.split_at(len);
+            body = rest;
+            match (tag, len) {
+                (CAPABILITY_MAX_BITRATE, 4) => capabilities.max_bitrate = value.get_u32(),
+                (CAPABILITY_FRAME_DURATIONS, _) => {
+                    capabilities.frame_durations = value
+                        .iter()
+                        .filter_map(|&millis| FrameDuration::from_millis(millis as u64))
+                        .collect();
+                }
+                (CAPABILITY_PREFERRED_FRAME_DURATION, 1) => {
+                    if let Some(duration) = FrameDuration::from_millis(value[0] as u64) {
+                        capabilities.preferred_frame_duration = duration;
+                    }
+                }
+                (CAPABILITY_CHANNELS, 1) => capabilities.channels = value[0],
+                (CAPABILITY_FEC, 1) => capabilities.fec = value[0] != 0,
+                (CAPABILITY_DTX, 1) => capabilities.dtx = value[0] != 0,
+                _ => {}
+            }
+        }
+        Ok(Hello {
+            version,
+            capabilities,
+        })
+    }
+}
+
+/// Agrees on the parameters of a call from the hellos of both sides. Both
+/// sides reach the same result, as long as each passes its own `role`.
+///
+/// The frame duration is the caller's preference if the callee supports it,
+/// else the callee's if the caller supports it, else the longest one both
+/// support. Everything else is the lesser of both sides.
+pub fn negotiate(role: Role, local: &Hello, remote: &Hello) -> Result<CallParameters, Error> {
+    let (caller, callee) = match role {
+        Role::Caller => (&local.capabilities, &remote.capabilities),
+        Role::Callee => (&remote.capabilities, &local.capabilities),
+    };
+
+    let frame_duration = if callee
+        .frame_durations
+        .contains(&caller.preferred_frame_duration)
+    {
+        Some(caller.preferred_frame_duration)
+    } else if caller
+        .frame_durations
+        .contains(&callee.preferred_frame_duration)
+    {
+        Some(callee.preferred_frame_duration)
+    } else {
+        caller
+            .frame_durations
+            .iter()
+            .filter(|duration| callee.frame_durations.contains(duration))
+            .max()
+            .copied()
+    };
+    let frame_duration = frame_duration.ok_or(Error::NoCommonFrameDuration)?;
+
+    let channels = caller.channels.min(callee.channels);
+    if channels == 0 {
+        return Err(Error::NoCommonChannelCount);
+    }
+
+    Ok(CallParameters {
+        version: local.version.min(remote.version),
+        frame_duration,
+        channels,
+        max_bitrate: caller.max_bitrate.min(callee.max_bitrate),
+        fec: caller.fec && callee.fec,
+        dtx: caller.dtx && callee.dtx,
+    })
+}
+
+/// Message of the reliable control stream that runs next to the voice
+/// datagrams.
+///
+/// On the wire each message is a big-endian `u16` length, then a type byte
+/// and its payload. Messages of an unknown type are skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlMessage {
+    /// First message on the stream, in both directions.
+    Hello(Hello),
+    /// The peer stopped or started sending its microphone.
+    Mute {
+        muted: bool,
+    },
+    /// How the peer receives us.
+    Stats(LinkStats),
+    /// The peer hangs up; it closes the connection once we have read this.
+    Bye {
+        reason: String,
+    },
+    Keepalive,
+}
+
+impl ControlMessage {
+    /// The message with its length prefix.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut body = BytesMut::new();
+        match self {
+            ControlMessage::Hello(hello) => {
+                body.put_u8(HELLO);
+                hello.put(&mut body);
+            }
+            ControlMessage::Mute { muted } => {
+                body.put_u8(MUTE);
+                body.put_u8(*muted as u8);
+            }
+            ControlMessage::Stats(stats) => {
+                body.put_u8(STATS);
+                body.put_f32(stats.loss);
+                body.put_u32(stats.jitter.as_micros().min(u32::MAX as u128) as u32);
+                body.put_u32(stats.rtt.as_micros().min(u32::MAX as u128) as u32);
+            }
+            ControlMessage::Bye { reason } => {
+                body.put_u8(BYE);
+                let reason = reason.as_bytes();
+                body.put_slice(&reason[..reason.len().min(MAX_CONTROL_MESSAGE - 1)]);
+            }
+            ControlMessage::Keepalive => body.put_u8(KEEPALIVE),
+        }
+
+        let mut buf = BytesMut::with_capacity(2 + body.len());
+        buf.put_u16(body.len() as u16);
+        buf.put_slice(&body);
+        buf.freeze()
+    }
+
+    /// Parses a message body, length prefix excluded. Returns `None` for a
+    /// message type this version does not know.
+    pub fn parse(mut body: &[u8]) -> Result<Option<Self>, Error> {
+        if body.is_empty() {
+            return Err(Error::ControlMessageTooShort);
+        }
+        let kind = body.get_u8();
+        let message = match kind {
+            HELLO => ControlMessage::Hello(Hello::parse(body)?),
+            MUTE => {
+                if body.is_empty() {
+                    return Err(Error::ControlMessageTooShort);
+                }
+                ControlMessage::Mute {
+                    muted: body.get_u8() != 0,
+                }
+            }
+            STATS => {
+                if body.len() < 12 {
+                    return Err(Error::ControlMessageTooShort);
+                }
+                ControlMessage::Stats(LinkStats {
+                    loss: body.get_f32(),
+                    jitter: Duration::from_micros(body.get_u32() as u64),
+                    rtt: Duration::from_micros(body.get_u32() as u64),
+                })
+            }
+            BYE => ControlMessage::Bye {
+                reason: String::from_utf8_lossy(body).into_owned(),
+            },
+            KEEPALIVE => ControlMessage::Keepalive,
+            _ => return Ok(None),
+        };
+        Ok(Some(message))
+    }
+}
+
+/// Reads the next message; `None` once the peer has finished the stream.
+/// Messages of unknown types are skipped.
+pub async fn read_message(
+    recv: &mut (impl AsyncRead + Unpin),
+) -> anyhow::Result<Option<ControlMessage>> {
+    loop {
+        let len = match recv.read_u16().await {
+            Ok(len) => len as usize,
+            Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        if len > MAX_CONTROL_MESSAGE {
+            return Err(Error::ControlMessageTooLong(len).into());
+        }
+        let mut body = vec![0u8; len];
+        recv.read_exact(&mut body).await?;
+        if let Some(message) = ControlMessage::parse(&body)? {
+            return Ok(Some(message));
+        }
+    }
+}
+
+pub async fn write_message(
+    send: &mut (impl AsyncWrite + Unpin),
+    message: &ControlMessage,
+) -> std::io::Result<()> {
+    send.write_all(&message.to_bytes()).await?;
+    send.flush().await
+}
+
+/// Control task of a connection: says hello, sends `outgoing` messages and
+/// keepalives, and passes what the peer sends on to `incoming`.
+///
+/// After sending a bye it finishes its side of the stream and returns once
+/// the peer has finished too, or right away if the peer never said hello.
+pub(crate) async fn control(
+    connection: Arc<dyn Transport>,
+    role: Role,
+    hello: Hello,
+    mut outgoing: mpsc::Receiver<ControlMessage>,
+    incoming: mpsc::Sender<ControlMessage>,
+    mut cancelled: watch::Receiver<bool>,
+) {
+    if connection.protocol_version() < 2 {
+        // version 1 has no control stream
+        return;
+    }
+    let ControlStream { mut send, mut recv } = tokio::select! {
+        stream = connection.control_stream(role) => match stream {
+            Ok(stream) => stream,
+            Err(_) => return,
+        },
+        _ = cancelled.changed() => return,
+    };
+    if write_message(&mut send, &ControlMessage::Hello(hello))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    // reads are not cancel safe, so they get a task of their own
+    let (greeted_prod, greeted) = watch::channel(false);
+    let mut reader = tokio::task::spawn(async move {
+        while let Ok(Some(message)) = read_message(&mut recv).await {
+            if matches!(message, ControlMessage::Hello(_)) {
+                greeted_prod.send_replace(true);
+            }
+            if incoming.send(message).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+    let said_bye = loop {
+        tokio::select! {
+            message = outgoing.recv() => {
+                let Some(message) = message else {
+                    break false;
+                };
+                let bye = matches!(message, ControlMessage::Bye { .. });
+                if write_message(&mut send, &message).await.is_err() {
+                    break false;
+                }
+                if bye {
+                    let _ = send.shutdown().await;
+                    break true;
+                }
+            }
+            _ = keepalive.tick(), if *greeted.borrow() => {
+                if write_message(&mut send, &ControlMessage::Keepalive).await.is_err() {
+                    break false;
+                }
+            }
+            _ = &mut reader => return,
+            _ = cancelled.changed() => break false,
+        }
+    };
+
+    // after a bye the peer closes the connection, or finishes its side, once
+    // it has read it; nobody is listening for it if the peer never said hello
+    if said_bye && *greeted.borrow() {
+        let _ = reader.await;
+    } else {
+        reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(frame_durations: &[FrameDuration], preferred: FrameDuration) -> Hello {
+        Hello {
+            version: 2,
+            capabilities: Capabilities {
+                frame_durations: frame_durations.to_vec(),
+                preferred_frame_duration: preferred,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip() {
+        let messages = [
+            ControlMessage::Hello(Hello {
+                version: 2,
+                capabilities: Capabilities {
+                    frame_durations: FrameDuration::ALL.to_vec(),
+                    preferred_frame_duration: FrameDuration::Ms40,
+                    channels: 2,
+                    max_bitrate: 32_000,
+                    fec: false,
+                    dtx: true,
+                },
+            }),
+            ControlMessage::Mute { muted: true },
+            ControlMessage::Stats(LinkStats {
+                loss: 0.125,
+                jitter: Duration::from_millis(12),
+                rtt: Duration::from_millis(80),
+            }),
+            ControlMessage::Keepalive,
+            ControlMessage::Bye {
+                reason: "done".into(),
+            },
+        ];
+
+        let (mut send, mut recv) = tokio::io::duplex(MAX_CONTROL_MESSAGE);
+        for message in &messages {
+            write_message(&mut send, message).await.unwrap();
+        }
+        drop(send);
+
+        for message in &messages {
+            assert_eq!(
+                read_message(&mut recv).await.unwrap().as_ref(),
+                Some(message)
+            );
+        }
+        assert_eq!(read_message(&mut recv).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn read_skips_unknown_messages() {
+        let (mut send, mut recv) = tokio::io::duplex(MAX_CONTROL_MESSAGE);
+        send.write_all(&[0, 3, 0xff, 1, 2]).await.unwrap();
+        write_message(&mut send, &ControlMessage::Keepalive)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            read_message(&mut recv).await.unwrap(),
+            Some(ControlMessage::Keepalive)
+        );
+    }
+
+    #[tokio::test]
+    async fn read_rejects_oversized_messages() {
+        let (mut send, mut recv) = tokio::io::duplex(MAX_CONTROL_MESSAGE);
+        send.write_u16(MAX_CONTROL_MESSAGE as u16 + 1)
+            .await
+            .unwrap();
+
+        let error = read_message(&mut recv).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(Error::ControlMessageTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn hello_skips_unknown_capabilities() {
+        let mut body = BytesMut::new();
+        body.put_u8(3);
+        body.put_u8(0x7f);
+        body.put_u8(2);
+        body.put_u16(0xffff);
+        body.put_u8(CAPABILITY_CHANNELS);
+        body.put_u8(1);
+        body.put_u8(2);
+
+        let hello = Hello::parse(&body).unwrap();
+        assert_eq!(hello.version, 3);
+        assert_eq!(hello.capabilities.channels, 2);
+        assert_eq!(hello.capabilities.frame_durations, [FrameDuration::Ms20]);
+    }
+
+    #[test]
+    fn negotiate_prefers_the_caller() {
+        let caller = hello(&FrameDuration::ALL, FrameDuration::Ms10);
+        let callee = hello(&FrameDuration::ALL, FrameDuration::Ms60);
+
+        let params = negotiate(Role::Caller, &caller, &callee).unwrap();
+        assert_eq!(params.frame_duration, FrameDuration::Ms10);
+        // both sides agree
+        assert_eq!(negotiate(Role::Callee, &callee, &caller).unwrap(), params);
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_the_callee_then_the_longest_common() {
+        let caller = hello(
+            &[FrameDuration::Ms20, FrameDuration::Ms40],
+            FrameDuration::Ms20,
+        );
+        let callee = hello(&[FrameDuration::Ms40], FrameDuration::Ms40);
+        let params = negotiate(Role::Caller, &caller, &callee).unwrap();
+        assert_eq!(params.frame_duration, FrameDuration::Ms40);
+
+        let caller = hello(
+            &[
+                FrameDuration::Ms10,
+                FrameDuration::Ms20,
+                FrameDuration::Ms40,
+            ],
+            FrameDuration::Ms10,
+        );
+        let callee = hello(
+            &[
+                FrameDuration::Ms20,
+                FrameDuration::Ms40,
+                FrameDuration::Ms60,
+            ],
+            FrameDuration::Ms60,
+        );
+        let params = negotiate(Role::Caller, &caller, &callee).unwrap();
+        assert_eq!(params.frame_duration, FrameDuration::Ms40);
+    }
+
+    #[test]
+    fn negotiate_takes_the_lesser_of_both_sides() {
+        let mut caller = hello(&[FrameDuration::Ms20], FrameDuration::Ms20);
+        caller.version = 3;
+        caller.capabilities.max_bitrate = 24_000;
+        caller.capabilities.dtx = true;
+        let mut callee = hello(&[FrameDuration::Ms20], FrameDuration::Ms20);
+        callee.capabilities.channels = 2;
+        callee.capabilities.fec = false;
+
+        let params = negotiate(Role::Caller, &caller, &callee).unwrap();
+        assert_eq!(
+            params,
+            CallParameters {
+                version: 2,
+                frame_duration: FrameDuration::Ms20,
+                channels: 1,
+                max_bitrate: 24_000,
+                fec: false,
+                dtx: false,
+            }
+        );
+    }
+
+    #[test]
+    fn negotiate_fails_without_common_ground() {
+        let caller = hello(&[FrameDuration::Ms10], FrameDuration::Ms10);
+        let callee = hello(&[FrameDuration::Ms60], FrameDuration::Ms60);
+        assert!(matches!(
+            negotiate(Role::Caller, &caller, &callee),
+            Err(Error::NoCommonFrameDuration)
+        ));
+
+        let mut callee = caller.clone();
+        callee.capabilities.channels = 0;
+        assert!(matches!(
+            negotiate(Role::Caller, &caller, &callee),
+            Err(Error::NoCommonChannelCount)
+        ));
+    }
+}
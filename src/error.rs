@@ -0,0 +1,34 @@
+use iroh::EndpointId;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Error)]
+pub enum Error {
+    #[error("rtp packet too short")]
+    RtpPacketTooShort,
+    #[error("unsupported rtp version {0}")]
+    UnsupportedRtpVersion(u8),
+    #[error("invalid rtp padding")]
+    InvalidRtpPadding,
+    #[error("rtp packet without payload")]
+    EmptyRtpPayload,
+    #[error("rtcp packet too short")]
+    RtcpPacketTooShort,
+    #[error("unsupported rtcp packet type {0}")]
+    UnsupportedRtcpPacketType(u8),
+    #[error("control message too short")]
+    ControlMessageTooShort,
+    #[error("control message of {0} bytes is too long")]
+    ControlMessageTooLong(usize),
+    #[error("no frame duration both sides support")]
+    NoCommonFrameDuration,
+    #[error("no channel count both sides support")]
+    NoCommonChannelCount,
+    #[error("conference bridge closed")]
+    BridgeClosed,
+    #[error("no call with {0}")]
+    UnknownPeer(EndpointId),
+    #[error("invalid volume {0}")]
+    InvalidVolume(f32),
+    #[error("shutdown failed: {}", .0.join("; "))]
+    Shutdown(Vec<String>),
+}
@@ -0,0 +1,85 @@
+use iroh::{EndpointId, endpoint::ConnectionError};
+
+use crate::{
+    StreamId,
+    control::CallParameters,
+    link::{LinkQuality, LinkStats},
+};
+
+/// Something the UI may want to show about the call; see `AudioServices::subscribe`.
+#[derive(Debug, Clone)]
+pub enum AudioEvent {
+    PeerConnected {
+        endpoint_id: EndpointId,
+    },
+    PeerDisconnected {
+        endpoint_id: EndpointId,
+        reason: DisconnectReason,
+    },
+    /// Both sides have agreed on the parameters of the call.
+    CallNegotiated {
+        endpoint_id: EndpointId,
+        parameters: CallParameters,
+    },
+    /// A remote stream started or stopped carrying speech.
+    PeerSpeaking {
+        stream_id: StreamId,
+        speaking: bool,
+    },
+    LinkQualityChanged {
+        endpoint_id: EndpointId,
+        quality: LinkQuality,
+        stats: LinkStats,
+    },
+    /// The peer reports a change in how well it receives us.
+    RemoteLinkQualityChanged {
+        endpoint_id: EndpointId,
+        quality: LinkQuality,
+        stats: LinkStats,
+    },
+    /// The peer muted or unmuted its microphone.
+    PeerMuted {
+        endpoint_id: EndpointId,
+        muted: bool,
+    },
+    /// The sound card reported an error; audio may have stopped.
+    DeviceError(hacore::error::Error),
+    /// A stage of the playback pipeline is not keeping up and drops frames.
+    PipelineStalled(PipelineStage),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Hung up by `remove_connection` or `shutdown`.
+    LocalHangup,
+    /// The peer closed the connection with that application close code.
+    RemoteHangup(u64),
+    /// The peer said bye on the control stream, for that reason.
+    RemoteBye(String),
+    /// The peers could not agree on the call, for that reason.
+    Incompatible(String),
+    TimedOut,
+    /// Any other connection failure.
+    Lost(String),
+}
+
+impl From<ConnectionError> for DisconnectReason {
+    fn from(error: ConnectionError) -> Self {
+        match error {
+            ConnectionError::LocallyClosed => DisconnectReason::LocalHangup,
+            ConnectionError::ApplicationClosed(close) => {
+                DisconnectReason::RemoteHangup(close.error_code.into_inner())
+            }
+            ConnectionError::TimedOut => DisconnectReason::TimedOut,
+            error => DisconnectReason::Lost(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineStage {
+    /// A decoder thread does not take commands as fast as the playout clock.
+    Decoder,
+    /// The mixer, paced by the sound card, does not take decoded frames.
+    Mixer,
+}
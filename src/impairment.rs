@@ -0,0 +1,366 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use iroh::EndpointId;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tokio::sync::{Mutex, mpsc};
+
+use crate::{
+    event::DisconnectReason,
+    transport::{BoxFuture, ControlStream, PathStats, Role, Transport},
+};
+
+/// Longest a datagram may wait for a bandwidth-capped link before it is dropped.
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(250);
+
+/// Two-state burst loss model: the link flips between a good and a bad
+/// state once per datagram and loses datagrams at the rate of its state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GilbertElliott {
+    pub good_to_bad: f64,
+    pub bad_to_good: f64,
+    pub loss_good: f64,
+    pub loss_bad: f64,
+}
+
+/// How badly a link behaves. Probabilities are between 0.0 and 1.0; the
+/// default is a perfect link.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Impairment {
+    /// Independent loss of each datagram.
+    pub loss: f64,
+    /// Burst loss, on top of `loss`.
+    pub burst_loss: Option<GilbertElliott>,
+    pub delay: Duration,
+    /// Extra delay drawn uniformly up to this value, so datagrams may
+    /// overtake each other.
+    pub jitter: Duration,
+    /// Chance for a datagram to be held back by `reorder_delay` more.
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    pub duplicate: f64,
+    /// Link capacity in bits per second.
+    pub bandwidth: Option<u64>,
+    pub seed: u64,
+}
+
+/// Decides the fate of each datagram of an impaired link. Given the same
+/// seed and arrival times it always decides the same.
+pub struct NetworkSimulator {
+    impairment: Impairment,
+    rng: StdRng,
+    bad_state: bool,
+    link_free_at: Option<Instant>,
+}
+
+impl NetworkSimulator {
+    pub fn new(impairment: Impairment) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(impairment.seed),
+            impairment,
+            bad_state: false,
+            link_free_at: None,
+        }
+    }
+
+    /// Returns when the copies of a `size`-byte datagram sent at `now` arrive:
+    /// none if it is lost, two if it is duplicated.
+    pub fn schedule(&mut self, size: usize, now: Instant) -> Vec<Instant> {
+        if self.lost() {
+            return Vec::new();
+        }
+
+        let mut departure = now;
+        if let Some(bandwidth) = self.impairment.bandwidth {
+            departure = self.link_free_at.map_or(now, |free_at| free_at.max(now));
+            if departure - now > MAX_QUEUE_DELAY {
+                return Vec::new();
+            }
+            let transmission = Duration::from_secs_f64(size as f64 * 8.0 / bandwidth as f64);
+            self.link_free_at = Some(departure + transmission);
+        }
+
+        let mut arrivals = vec![departure + self.latency()];
+        if self.rng.random::<f64>() < self.impairment.duplicate {
+            arrivals.push(departure + self.latency());
+        }
+        arrivals
+    }
+
+    fn lost(&mut self) -> bool {
+        let mut loss = self.impairment.loss;
+        if let Some(burst) = self.impairment.burst_loss {
+            let flip = if self.bad_state {
+                burst.bad_to_good
+            } else {
+                burst.good_to_bad
+            };
+            if self.rng.random::<f64>() < flip {
+                self.bad_state = !self.bad_state;
+            }
+            let burst_loss = if self.bad_state {
+                burst.loss_bad
+            } else {
+                burst.loss_good
+            };
+            loss = 1.0 - (1.0 - loss) * (1.0 - burst_loss);
+        }
+        self.rng.random::<f64>() < loss
+    }
+
+    fn latency(&mut self) -> Duration {
+        let mut latency = self.impairment.delay;
+        if !self.impairment.jitter.is_zero() {
+            latency += self.impairment.jitter.mul_f64(self.rng.random::<f64>());
+        }
+        if self.rng.random::<f64>() < self.impairment.reorder {
+            latency += self.impairment.reorder_delay;
+        }
+        latency
+    }
+}
+
+/// Wraps a transport and impairs the datagrams received through it. Wrap
+/// both ends of a link to impair both directions.
+///
+/// Must be created inside a tokio runtime.
+pub struct ImpairedTransport<T> {
+    inner: Arc<T>,
+    delay: Duration,
+    delivered: Mutex<Delivery>,
+    pump: tokio::task::JoinHandle<()>,
+}
+
+struct Delivery {
+    datagrams: mpsc::UnboundedReceiver<Result<Bytes, DisconnectReason>>,
+    /// Repeated to every read once the inner transport has failed.
+    ended: Option<DisconnectReason>,
+}
+
+impl<T> Drop for ImpairedTransport<T> {
+    fn drop(&mut self) {
+        self.pump.abort();
+    }
+}
+
+impl<T: Transport> ImpairedTransport<T> {
+    pub fn new(inner: T, impairment: Impairment) -> Self {
+        let inner = Arc::new(inner);
+        let delay = impairment.delay;
+        let (delivered_prod, datagrams) = mpsc::unbounded_channel();
+        let pump = tokio::task::spawn(pump(
+            inner.clone(),
+            NetworkSimulator::new(impairment),
+            delivered_prod,
+        ));
+        Self {
+            inner,
+            delay,
+            delivered: Mutex::new(Delivery {
+                datagrams,
+                ended: None,
+            }),
+            pump,
+        }
+    }
+}
+
+async fn pump<T: Transport>(
+    inner: Arc<T>,
+    mut network: NetworkSimulator,
+    delivered: mpsc::UnboundedSender<Result<Bytes, DisconnectReason>>,
+) {
+    loop {
+        let datagram = match inner.read_datagram().await {
+            Ok(datagram) => datagram,
+            Err(reason) => {
+                let _ = delivered.send(Err(reason));
+                return;
+            }
+        };
+        for arrival in network.schedule(datagram.len(), Instant::now()) {
+            let delivered = delivered.clone();
+            let datagram = datagram.clone();
+            tokio::task::spawn(async move {
+                tokio::time::sleep_until(arrival.into()).await;
+                let _ = delivered.send(Ok(datagram));
+            });
+        }
+    }
+}
+
+impl<T: Transport> Transport for ImpairedTransport<T> {
+    fn remote_id(&self) -> EndpointId {
+        self.inner.remote_id()
+    }
+
+    fn send_datagram(&self, datagram: Bytes) -> Result<(), DisconnectReason> {
+        self.inner.send_datagram(datagram)
+    }
+
+    fn read_datagram(&self) -> BoxFuture<'_, Result<Bytes, DisconnectReason>> {
+        Box::pin(async move {
+            let mut delivered = self.delivered.lock().await;
+            if let Some(reason) = &delivered.ended {
+                return Err(reason.clone());
+            }
+            let datagram = delivered.datagrams.recv().await;
+            // the pump only stops after sending the error
+            let datagram = datagram.unwrap_or(Err(DisconnectReason::LocalHangup));
+            if let Err(reason) = &datagram {
+                delivered.ended = Some(reason.clone());
+            }
+            datagram
+        })
+    }
+
+    fn control_stream(&self, role: Role) -> BoxFuture<'_, Result<ControlStream, DisconnectReason>> {
+        // retransmissions hide any loss from a reliable stream anyway
+        self.inner.control_stream(role)
+    }
+
+    fn close(&self, code: u32, reason: &[u8]) {
+        self.inner.close(code, reason)
+    }
+
+    fn rtt(&self) -> Duration {
+        // the delay applies to one direction only
+        self.inner.rtt() + self.delay
+    }
+
+    fn protocol_version(&self) -> u8 {
+        self.inner.protocol_version()
+    }
+
+    fn path_stats(&self) -> PathStats {
+        // losses simulated here happen past the inner transport's view
+        PathStats {
+            rtt: self.rtt(),
+            ..self.inner.path_stats()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATAGRAMS: usize = 10_000;
+    const INTERVAL: Duration = Duration::from_millis(20);
+
+    /// What the simulator decides for a datagram sent every `INTERVAL`.
+    fn run(impairment: Impairment, start: Instant) -> Vec<Vec<Instant>> {
+        let mut network = NetworkSimulator::new(impairment);
+        (0..DATAGRAMS)
+            .map(|i| network.schedule(100, start + INTERVAL * i as u32))
+            .collect()
+    }
+
+    fn everything(seed: u64) -> Impairment {
+        Impairment {
+            loss: 0.05,
+            burst_loss: Some(GilbertElliott {
+                good_to_bad: 0.01,
+                bad_to_good: 0.3,
+                loss_good: 0.0,
+                loss_bad: 0.8,
+            }),
+            delay: Duration::from_millis(40),
+            jitter: Duration::from_millis(30),
+            reorder: 0.05,
+            reorder_delay: Duration::from_millis(60),
+            duplicate: 0.05,
+            bandwidth: None,
+            seed,
+        }
+    }
+
+    #[test]
+    fn perfect_link_delivers_everything_once() {
+        let start = Instant::now();
+        let decisions = run(Impairment::default(), start);
+        for (i, arrivals) in decisions.iter().enumerate() {
+            assert_eq!(arrivals, &[start + INTERVAL * i as u32]);
+        }
+    }
+
+    #[test]
+    fn same_seed_decides_the_same() {
+        let start = Instant::now();
+        let decisions = run(everything(7), start);
+        assert_eq!(decisions, run(everything(7), start));
+        assert_ne!(decisions, run(everything(8), start));
+
+        // every kind of decision was taken
+        assert!(decisions.iter().any(Vec::is_empty));
+        assert!(decisions.iter().any(|arrivals| arrivals.len() == 2));
+        let latencies: Vec<_> = decisions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, arrivals)| Some(*arrivals.first()? - (start + INTERVAL * i as u32)))
+            .collect();
+        assert!(
+            latencies
+                .iter()
+                .all(|&latency| latency >= Duration::from_millis(40))
+        );
+        assert!(
+            latencies
+                .iter()
+                .any(|&latency| latency > Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn gilbert_elliott_losses_come_in_bursts() {
+        let burst = GilbertElliott {
+            good_to_bad: 0.02,
+            bad_to_good: 0.25,
+            loss_good: 0.0,
+            loss_bad: 1.0,
+        };
+        let impairment = Impairment {
+            burst_loss: Some(burst),
+            seed: 1,
+            ..Default::default()
+        };
+        let lost: Vec<bool> = run(impairment, Instant::now())
+            .iter()
+            .map(Vec::is_empty)
+            .collect();
+
+        // the link spends good_to_bad / (good_to_bad + bad_to_good) of the
+        // time in the bad state, about 7.4 %
+        let loss = lost.iter().filter(|&&lost| lost).count() as f64 / DATAGRAMS as f64;
+        assert!((0.05..0.10).contains(&loss), "loss {loss}");
+
+        // bad spells last 1 / bad_to_good datagrams on average, 4, where
+        // independent loss at that rate would hardly lose two in a row
+        let bursts = lost
+            .windows(2)
+            .filter(|pair| pair == &[false, true])
+            .count();
+        let mean_burst = loss * DATAGRAMS as f64 / bursts as f64;
+        assert!((3.0..5.0).contains(&mean_burst), "mean burst {mean_burst}");
+    }
+
+    #[test]
+    fn bandwidth_cap_queues_then_drops() {
+        let impairment = Impairment {
+            // a 100-byte datagram takes 100 ms
+            bandwidth: Some(8_000),
+            ..Default::default()
+        };
+        let mut network = NetworkSimulator::new(impairment);
+        let now = Instant::now();
+        for queued in 0..3 {
+            let arrivals = network.schedule(100, now);
+            assert_eq!(arrivals, [now + Duration::from_millis(100) * queued]);
+        }
+        // it would wait longer than MAX_QUEUE_DELAY
+        assert!(network.schedule(100, now).is_empty());
+    }
+}
@@ -0,0 +1,340 @@
+use std::{collections::BTreeMap, time::Instant};
+
+use crate::{
+    DecodeCommand,
+    rtp::{COMFORT_NOISE_PAYLOAD_TYPE, RTP_CLOCK_RATE, ReportBlock, RtpPacket},
+};
+
+/// Lower/upper bound of the playout delay, in frames.
+pub const MIN_TARGET_DELAY: usize = 1;
+pub const MAX_TARGET_DELAY: usize = 15;
+/// How many times the measured jitter is kept as safety margin.
+const JITTER_MARGIN: f32 = 3.0;
+/// Frames the buffer may run above its target before it drops the oldest one.
+const DRAIN_SLACK: usize = 2;
+/// Consecutive concealed frames after which the stream counts as stopped and
+/// the buffer goes back to pre-buffering.
+const MAX_CONCEALED_FRAMES: usize = 5;
+/// Keeps extended sequence numbers away from zero so that packets older than
+/// the first one received can still be placed.
+const SEQUENCE_BASE: u64 = 1 << 32;
+
+/// Reorders the packets of one remote stream by sequence number and hands
+/// them to the decoder at a fixed playout rate.
+///
+/// The target delay follows the RFC 3550 interarrival jitter estimate. Every
+/// `pop` yields one decode command: the packet itself if it is there, FEC
+/// from the following packet if only that one is there, PLC otherwise, and
+/// silence while pre-buffering, so the decoder keeps a fixed cadence.
+///
+/// After a comfort noise packet the sender pauses on purpose: the gap plays
+/// out as comfort noise until the next talkspurt has been buffered.
+pub struct JitterBuffer {
+    frame_samples: u32,
+    packets: BTreeMap<u64, RtpPacket>,
+    highest_seq: Option<u64>,
+    next_seq: u64,
+    last_played: Option<u64>,
+    playing: bool,
+    concealed: usize,
+    /// Noise level of the pause the sender is in, if any.
+    comfort_noise: Option<u8>,
+
+    clock_base: Instant,
+    last_transit: Option<u32>,
+    jitter: f32,
+    target_delay: usize,
+
+    ssrc: u32,
+    first_seq: Option<u64>,
+    received: u64,
+    expected_prior: u64,
+    received_prior: u64,
+}
+
+impl JitterBuffer {
+    /// `frame_samples`: media clock ticks covered by one packet and one playout tick.
+    pub fn new(frame_samples: u32) -> Self {
+        Self {
+            frame_samples,
+            packets: BTreeMap::new(),
+            highest_seq: None,
+            next_seq: 0,
+            last_played: None,
+            playing: false,
+            concealed: 0,
+            comfort_noise: None,
+            clock_base: Instant::now(),
+            last_transit: None,
+            jitter: 0.0,
+            target_delay: MIN_TARGET_DELAY,
+            ssrc: 0,
+            first_seq: None,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+        }
+    }
+
+    /// For a stream whose sender switched to another frame duration.
+    pub fn set_frame_samples(&mut self, frame_samples: u32) {
+        self.frame_samples = frame_samples;
+    }
+
+    /// Interarrival jitter in media clock ticks.
+    pub fn jitter(&self) -> f32 {
+        self.jitter
+    }
+
+    /// Current target playout delay, in frames.
+    pub fn target_delay(&self) -> usize {
+        self.target_delay
+    }
+
+    pub fn push(&mut self, packet: RtpPacket, arrival: Instant) {
+        let seq = self.extend_sequence(packet.header.sequence);
+        self.ssrc = packet.header.ssrc;
+        self.first_seq.get_or_insert(seq);
+        self.received += 1;
+        if self.highest_seq.is_none_or(|highest| seq > highest) {
+            self.highest_seq = Some(seq);
+        }
+
+        if self.last_played.is_some_and(|played| seq <= played) {
+            // too late, its slot has already been concealed
+            return;
+        }
+
+        self.update_jitter(packet.header.timestamp, arrival);
+        self.packets.entry(seq).or_insert(packet);
+    }
+
+    /// Reception statistics since the previous call, for a receiver report
+    /// (RFC 3550 A.3). `None` until a packet has arrived.
+    pub fn report(&mut self) -> Option<ReportBlock> {
+        let expected = self.highest_seq? - self.first_seq? + 1;
+        let expected_interval = expected.saturating_sub(self.expected_prior);
+        let received_interval = self.received - self.received_prior;
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        let fraction_lost = match expected_interval {
+            0 => 0,
+            _ => ((lost_interval << 8) / expected_interval).min(255) as u8,
+        };
+        Some(ReportBlock {
+            ssrc: self.ssrc,
+            fraction_lost,
+            cumulative_lost: (expected as i64 - self.received as i64)
+                .clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+            highest_sequence: self.highest_seq? as u32,
+            jitter: self.jitter as u32,
+        })
+    }
+
+    /// Called once per playout tick.
+    pub fn pop(&mut self) -> DecodeCommand {
+        if !self.playing {
+            let first = self.packets.first_key_value().map(|(&first, _)| first);
+            match first {
+                Some(first) if self.buffered_frames(first) >= self.target_delay => {
+                    self.playing = true;
+                    self.next_seq = first;
+                }
+                _ => {
+                    return self
+                        .comfort_noise
+                        .map_or(DecodeCommand::Silence, DecodeCommand::ComfortNoise);
+                }
+            }
+        }
+
+        if let Some(packet) = self.packets.remove(&self.next_seq) {
+            self.advance();
+            self.concealed = 0;
+            if packet.header.payload_type == COMFORT_NOISE_PAYLOAD_TYPE {
+                // the next talkspurt pre-buffers like the first one
+                let level = packet.payload.first().copied().unwrap_or(127);
+                self.comfort_noise = Some(level);
+                self.playing = false;
+                return DecodeCommand::ComfortNoise(level);
+            }
+            self.comfort_noise = None;
+            self.drain();
+            return DecodeCommand::DecodeNormal(packet.payload);
+        }
+
+        self.concealed += 1;
+        if self.concealed > MAX_CONCEALED_FRAMES {
+            self.playing = false;
+            self.concealed = 0;
+        }
+
+        match self.packets.get(&(self.next_seq + 1)) {
+            Some(next) if next.header.payload_type != COMFORT_NOISE_PAYLOAD_TYPE => {
+                // the redundancy in the next packet rebuilds this one
+                let command = DecodeCommand::DecodeFEC(next.payload.clone());
+                self.advance();
+                command
+            }
+            None if self.packets.is_empty() => {
+                // nothing newer arrived either: the packet is more likely late
+                // than lost, so hold the slot and let the delay grow by a frame
+                DecodeCommand::DecodePLC
+            }
+            _ => {
+                self.advance();
+                DecodeCommand::DecodePLC
+            }
+        }
+    }
+
+    fn advance(&mut self) {
+        self.last_played = Some(self.next_seq);
+        self.next_seq += 1;
+    }
+
+    /// Drops the oldest packets once the buffer runs too far above its target.
+    fn drain(&mut self) {
+        while self.buffered_frames(self.next_seq) > self.target_delay + DRAIN_SLACK {
+            self.packets.remove(&self.next_seq);
+            self.advance();
+        }
+    }
+
+    fn buffered_frames(&self, from: u64) -> usize {
+        match self.highest_seq {
+            Some(highest) if highest >= from => (highest - from + 1) as usize,
+            _ => 0,
+        }
+    }
+
+    fn extend_sequence(&self, seq: u16) -> u64 {
+        match self.highest_seq {
+            None => SEQUENCE_BASE + seq as u64,
+            Some(highest) => {
+                let delta = seq.wrapping_sub(highest as u16) as i16 as i64;
+                (highest as i64 + delta) as u64
+            }
+        }
+    }
+
+    /// RFC 3550 A.8: J += (|D| - J) / 16
+    fn update_jitter(&mut self, timestamp: u32, arrival: Instant) {
+        let arrival = arrival
+            .saturating_duration_since(self.clock_base)
+            .as_secs_f64()
+            * RTP_CLOCK_RATE as f64;
+        let transit = (arrival as u64 as u32).wrapping_sub(timestamp);
+
+        if let Some(last_transit) = self.last_transit {
+            let d = transit.wrapping_sub(last_transit) as i32;
+            self.jitter += ((d as f32).abs() - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+
+        let margin = (JITTER_MARGIN * self.jitter / self.frame_samples as f32).ceil() as usize;
+        self.target_delay = (MIN_TARGET_DELAY + margin).clamp(MIN_TARGET_DELAY, MAX_TARGET_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::rtp::{OPUS_PAYLOAD_TYPE, RtpHeader};
+
+    const FRAME: u32 = 960;
+
+    fn packet(sequence: u16, payload_type: u8) -> RtpPacket {
+        RtpPacket {
+            header: RtpHeader {
+                marker: false,
+                payload_type,
+                sequence,
+                // one timestamp for all, arriving together: no jitter, and the
+                // target delay stays at its minimum
+                timestamp: 0,
+                ssrc: 1,
+            },
+            payload: Bytes::from(vec![sequence as u8]),
+        }
+    }
+
+    fn buffer(sequences: &[u16]) -> JitterBuffer {
+        let mut jitter = JitterBuffer::new(FRAME);
+        let arrival = Instant::now();
+        for &sequence in sequences {
+            jitter.push(packet(sequence, OPUS_PAYLOAD_TYPE), arrival);
+        }
+        jitter
+    }
+
+    fn decoded(command: DecodeCommand) -> Option<u8> {
+        match command {
+            DecodeCommand::DecodeNormal(payload) => Some(payload[0]),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn silent_while_pre_buffering() {
+        let mut jitter = buffer(&[]);
+        assert!(matches!(jitter.pop(), DecodeCommand::Silence));
+    }
+
+    #[test]
+    fn reorders_packets() {
+        let mut jitter = buffer(&[11, 10, 12]);
+        assert_eq!(jitter.target_delay(), MIN_TARGET_DELAY);
+        assert_eq!(decoded(jitter.pop()), Some(10));
+        assert_eq!(decoded(jitter.pop()), Some(11));
+        assert_eq!(decoded(jitter.pop()), Some(12));
+    }
+
+    #[test]
+    fn rebuilds_a_loss_from_the_next_packet() {
+        let mut jitter = buffer(&[0, 2]);
+        assert_eq!(decoded(jitter.pop()), Some(0));
+        match jitter.pop() {
+            DecodeCommand::DecodeFEC(payload) => assert_eq!(payload[0], 2),
+            command => panic!("expected FEC, got {command:?}"),
+        }
+        assert_eq!(decoded(jitter.pop()), Some(2));
+    }
+
+    #[test]
+    fn conceals_a_loss_the_next_packet_cannot_rebuild() {
+        let mut jitter = buffer(&[0, 3]);
+        assert_eq!(decoded(jitter.pop()), Some(0));
+        assert!(matches!(jitter.pop(), DecodeCommand::DecodePLC));
+        assert!(matches!(jitter.pop(), DecodeCommand::DecodeFEC(_)));
+        assert_eq!(decoded(jitter.pop()), Some(3));
+    }
+
+    #[test]
+    fn holds_the_slot_of_a_late_packet() {
+        let mut jitter = buffer(&[0]);
+        assert_eq!(decoded(jitter.pop()), Some(0));
+        assert!(matches!(jitter.pop(), DecodeCommand::DecodePLC));
+
+        jitter.push(packet(1, OPUS_PAYLOAD_TYPE), Instant::now());
+        assert_eq!(decoded(jitter.pop()), Some(1));
+    }
+
+    #[test]
+    fn plays_comfort_noise_through_a_pause() {
+        let mut jitter = JitterBuffer::new(FRAME);
+        let mut noise = packet(0, COMFORT_NOISE_PAYLOAD_TYPE);
+        noise.payload = Bytes::from_static(&[40]);
+        jitter.push(noise, Instant::now());
+
+        assert!(matches!(jitter.pop(), DecodeCommand::ComfortNoise(40)));
+        assert!(matches!(jitter.pop(), DecodeCommand::ComfortNoise(40)));
+
+        jitter.push(packet(1, OPUS_PAYLOAD_TYPE), Instant::now());
+        assert_eq!(decoded(jitter.pop()), Some(1));
+    }
+}
@@ -1,128 +1,887 @@
+pub mod bridge;
 pub mod build;
+pub mod codec;
+pub mod congestion;
+pub mod control;
+pub mod error;
+pub mod event;
+pub mod impairment;
+pub mod jitter;
+pub mod link;
+pub mod mixer;
+pub mod rtp;
+pub mod sfu;
+pub mod transport;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
-#[cfg(target_vendor = "apple")]
-use hacore::EngineBuilder;
-use hacore::{AudioEngine, FRAME20MS};
-use iroh::{EndpointId, endpoint::Connection};
-use tokio::sync::{broadcast, mpsc};
+use hacore::{
+    AudioEngine, AudioPipeline, EngineBuilder, SAMPLE_RATE,
+    devices::DeviceSelection,
+    file_audio_engine::{FileAudioEngine, FileEngineConfig},
+};
+use iroh::EndpointId;
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::{
+    codec::{ComfortNoise, FrameDuration, noise_level},
+    congestion::{
+        BitrateBounds, CongestionController, EncoderSettings, LinkFeedback, START_BITRATE,
+    },
+    control::{
+        CONTROL_TIMEOUT, CallParameters, Capabilities, ControlMessage, Hello, control, negotiate,
+    },
+    error::Error,
+    event::{AudioEvent, DisconnectReason, PipelineStage},
+    jitter::JitterBuffer,
+    link::LinkMonitor,
+    mixer::Mixer,
+    rtp::{
+        COMFORT_NOISE_PAYLOAD_TYPE, OPUS_PAYLOAD_TYPE, ReceiverReport, RtpPacket, RtpSequencer,
+        is_rtcp,
+    },
+    transport::{Role, Transport},
+};
+
+/// Newest protocol version this build speaks. Version 1 is the voice
+/// datagrams alone, version 2 adds the control stream and its handshake.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// ALPN of `PROTOCOL_VERSION`.
+pub const ALPN: &[u8] = b"hacat/opus/2";
+pub const ALPN_V1: &[u8] = b"hacat/opus/1";
+/// ALPNs of every protocol version this build speaks, newest first.
+pub const ALPNS: &[&[u8]] = &[ALPN, ALPN_V1];
+/// Application close code sent when a call is hung up locally.
+pub const CLOSE_CODE_HANGUP: u32 = 0;
+/// Application close code sent when both sides could not agree on the call.
+pub const CLOSE_CODE_INCOMPATIBLE: u32 = 1;
+/// How long a hang-up waits for the peer to read the bye.
+pub const BYE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often a receiver reports the loss of the streams it receives.
+pub const RECEIVER_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a sender in DTX repeats its comfort noise packet, which keeps
+/// the stream alive and the noise level current.
+pub const COMFORT_NOISE_INTERVAL: Duration = Duration::from_millis(400);
+
+/// Protocol version of an ALPN out of `ALPNS`.
+pub fn alpn_version(alpn: &[u8]) -> Option<u8> {
+    let version = alpn.strip_prefix(b"hacat/opus/")?;
+    std::str::from_utf8(version).ok()?.parse().ok()
+}
 
 #[derive(Debug, Clone)]
 pub enum DecodeCommand {
     DecodeNormal(Bytes),
     DecodeFEC(Bytes),
     DecodePLC,
+    /// The sender pauses on purpose; fill in noise at that level in -dBov.
+    ComfortNoise(u8),
+    /// Nothing to play yet, the stream is still pre-buffering.
+    Silence,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AudioServicesConfig {
+    pub devices: DeviceSelection,
+    /// Runs on files instead of the sound card when set.
+    pub file_engine: Option<FileEngineConfig>,
+    /// Range congestion control moves the encoder bitrate in.
+    pub bitrate: BitrateBounds,
+    /// Packetization we ask peers for, and the mixer's frame size.
+    pub frame_duration: FrameDuration,
+    /// Echo cancellation and clean-up of the microphone.
+    pub pipeline: AudioPipeline,
 }
 
 pub struct AudioServices {
     pub ae: Arc<dyn AudioEngine>,
     send_data_cons: broadcast::Receiver<Bytes>,
-    decode_frame_prod: mpsc::Sender<DecodedFrame>,
-    pub mixer_thread: Arc<std::thread::JoinHandle<()>>,
+    encoder_command_prod: mpsc::Sender<EncoderCommand>,
+    /// SSRC of the outgoing stream, which also signs our receiver reports.
+    ssrc: u32,
+    bitrate: BitrateBounds,
+    frame_duration: FrameDuration,
+    mic: Arc<MicGate>,
+    deafened: Arc<AtomicBool>,
+    decode_frame_prod: mpsc::Sender<MixerCommand>,
+    /// Taken on shutdown, to be joined.
+    encoder_thread: Option<std::thread::JoinHandle<()>>,
+    mixer_thread: Option<std::thread::JoinHandle<()>>,
     connect_pair: HashMap<EndpointId, ConnectPair>,
+    events: broadcast::Sender<AudioEvent>,
 }
 
 pub struct ConnectPair {
-    pub connection: Connection,
+    pub connection: Arc<dyn Transport>,
     pub sender_thread: tokio::task::JoinHandle<()>,
     pub reciver_thread: tokio::task::JoinHandle<()>,
-    pub decoder_thread: std::thread::JoinHandle<()>,
+    pub control_thread: tokio::task::JoinHandle<()>,
+    /// Messages for the peer, over the control stream.
+    pub control: mpsc::Sender<ControlMessage>,
+    /// Signalled, or dropped, to stop the tasks.
+    pub cancel: watch::Sender<bool>,
+    /// Set before our bye, so that the peer closing in answer to it is not
+    /// taken for the peer hanging up.
+    pub hanging_up: Arc<AtomicBool>,
+}
+
+/// Whether the microphone reaches the encoder. Shared with the encoder
+/// thread, which sends silence while it is closed.
+#[derive(Debug, Default)]
+pub struct MicGate {
+    muted: AtomicBool,
+    push_to_talk: AtomicBool,
+    /// The push-to-talk key is held.
+    talking: AtomicBool,
+}
+
+impl MicGate {
+    pub fn is_open(&self) -> bool {
+        !self.muted.load(Ordering::Relaxed)
+            && (!self.push_to_talk.load(Ordering::Relaxed) || self.talking.load(Ordering::Relaxed))
+    }
+}
+
+/// What a receiver task exchanges with the control task of its connection.
+struct ControlChannel {
+    role: Role,
+    /// What we said in our hello, to negotiate against the peer's.
+    hello: Hello,
+    outgoing: mpsc::Sender<ControlMessage>,
+    incoming: mpsc::Receiver<ControlMessage>,
+}
+
+/// Input of the encoder thread, besides the microphone samples.
+#[derive(Debug, Clone)]
+pub enum EncoderCommand {
+    /// The endpoint has reported on the stream it gets from us.
+    Feedback {
+        endpoint_id: EndpointId,
+        feedback: LinkFeedback,
+    },
+    /// The call with the endpoint has been negotiated.
+    Negotiated {
+        endpoint_id: EndpointId,
+        parameters: CallParameters,
+    },
+    /// The endpoint has left; its reports no longer count.
+    RemoveEndpoint(EndpointId),
+}
+
+/// Input of the mixer thread.
+#[derive(Debug, Clone)]
+pub enum MixerCommand {
+    Frame(DecodedFrame),
+    /// Frees the inputs of every stream received from that endpoint.
+    RemoveEndpoint(EndpointId),
+    /// The endpoint has left for good; its volume and mute are dropped.
+    ForgetEndpoint(EndpointId),
+    SetVolume {
+        endpoint_id: EndpointId,
+        volume: f32,
+    },
+    SetMuted {
+        endpoint_id: EndpointId,
+        muted: bool,
+    },
+}
+
+/// Playout state of one logical stream received over a connection. A bridge
+/// in forwarding mode relays several of them over the same connection.
+struct RemoteStream {
+    jitter: JitterBuffer,
+    /// Audio in each of its packets, as the peer chose to packetize it.
+    frame: Duration,
+    /// Playout time owed to the stream and not handed out yet.
+    due: Duration,
+    decoder_input: mpsc::Sender<DecodeCommand>,
+    decoder_thread: std::thread::JoinHandle<()>,
+    last_arrival: Instant,
+    stalled: bool,
 }
 
+/// A stream silent for that long is considered gone and its decoder stopped.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+/// Receivers tick at the shortest frame duration, so that streams of any
+/// supported duration play out on time.
+const PLAYOUT_TICK: Duration = Duration::from_millis(10);
+/// Longest the encoder and the mixer sleep without the audio engine waking
+/// them, so that they still see their channels close once it is gone.
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+
 impl AudioServices {
     pub fn new() -> anyhow::Result<Self> {
-        let (ae_mic_output, encoder_input) = rtrb::RingBuffer::new(FRAME20MS * 4);
-        let (mixer_output, ae_ref_input) = rtrb::RingBuffer::new(FRAME20MS * 4);
+        Self::with_config(AudioServicesConfig::default())
+    }
+
+    pub fn with_config(config: AudioServicesConfig) -> anyhow::Result<Self> {
+        // the encoder reads frames of whatever duration the peers negotiate
+        let (ae_mic_output, encoder_input) =
+            rtrb::RingBuffer::new(FrameDuration::Ms60.samples() * 4);
+        // the mixer always writes frames of ours
+        let (mixer_output, ae_ref_input) =
+            rtrb::RingBuffer::new(config.frame_duration.samples() * 4);
 
+        let (events, _) = tokio::sync::broadcast::channel(64);
+
+        let ssrc = rand::random();
+        let mic = Arc::new(MicGate::default());
+        let deafened = Arc::new(AtomicBool::new(false));
         let (send_data_prod, send_data_cons) = tokio::sync::broadcast::channel(4);
-        let encoder_thread = build_encoder(encoder_input, send_data_prod)?;
-
-        let (decode_frame_prod, mixer_input) = tokio::sync::mpsc::channel(4);
-        let mixer_thread = build_mixer(mixer_input, mixer_output)?;
-        let mixer_thread = Arc::new(mixer_thread);
-
-        #[cfg(not(target_vendor = "apple"))]
-        let ae: Arc<dyn AudioEngine> = hacore::default_audio_engine::DefaultAudioEngine::build(
-            ae_mic_output,
-            ae_ref_input,
-            encoder_thread,
-            mixer_thread.clone(),
+        let (encoder_command_prod, encoder_commands) = tokio::sync::mpsc::channel(16);
+        let encoder_thread = build_encoder(
+            encoder_input,
+            send_data_prod,
+            encoder_commands,
+            ssrc,
+            config.bitrate,
+            config.frame_duration,
+            mic.clone(),
+        )?;
+
+        let (decode_frame_prod, mixer_input) = tokio::sync::mpsc::channel(64);
+        let mixer_thread = build_mixer(
+            mixer_input,
+            mixer_output,
+            config.frame_duration,
+            deafened.clone(),
+            events.clone(),
         )?;
-        #[cfg(target_vendor = "apple")]
-        let ae: Arc<dyn AudioEngine> =
-            hacore::apple_platform_audio_engine::ApplePlatformAudioEngine::build(
+
+        let device_events = events.clone();
+        let error_callback: hacore::ErrorCallback = Arc::new(move |error| {
+            let _ = device_events.send(AudioEvent::DeviceError(error));
+        });
+
+        let ae: Arc<dyn AudioEngine> = match &config.file_engine {
+            Some(file_engine) => FileAudioEngine::build(
+                ae_mic_output,
+                ae_ref_input,
+                encoder_thread.thread().clone(),
+                mixer_thread.thread().clone(),
+                file_engine,
+                config.pipeline,
+            )?,
+            #[cfg(not(target_vendor = "apple"))]
+            None => hacore::default_audio_engine::DefaultAudioEngine::build_with_devices(
                 ae_mic_output,
                 ae_ref_input,
-                encoder_thread,
-                mixer_thread.clone(),
-            )?;
+                encoder_thread.thread().clone(),
+                mixer_thread.thread().clone(),
+                error_callback,
+                &config.devices,
+                config.pipeline,
+            )?,
+            #[cfg(target_vendor = "apple")]
+            None => {
+                hacore::apple_platform_audio_engine::ApplePlatformAudioEngine::build_with_devices(
+                    ae_mic_output,
+                    ae_ref_input,
+                    encoder_thread.thread().clone(),
+                    mixer_thread.thread().clone(),
+                    error_callback,
+                    &config.devices,
+                    config.pipeline,
+                )?
+            }
+        };
 
         Ok(AudioServices {
             ae,
             connect_pair: HashMap::default(),
             send_data_cons,
+            encoder_command_prod,
+            ssrc,
+            bitrate: config.bitrate,
+            frame_duration: config.frame_duration,
+            mic,
+            deafened,
             decode_frame_prod,
-            mixer_thread,
+            encoder_thread: Some(encoder_thread),
+            mixer_thread: Some(mixer_thread),
+            events,
         })
     }
 
-    pub fn add_connection(&mut self, connection: Connection) -> anyhow::Result<()> {
+    /// Stops sending the microphone, and tells every peer so.
+    pub fn set_mic_muted(&mut self, muted: bool) {
+        if self.mic.muted.swap(muted, Ordering::Relaxed) == muted {
+            return;
+        }
+        for pair in self.connect_pair.values() {
+            let _ = pair.control.try_send(ControlMessage::Mute { muted });
+        }
+    }
+
+    pub fn is_mic_muted(&self) -> bool {
+        self.mic.muted.load(Ordering::Relaxed)
+    }
+
+    /// Silences what the peers say, locally; they are not told.
+    pub fn set_deafened(&self, deafened: bool) {
+        self.deafened.store(deafened, Ordering::Relaxed);
+    }
+
+    pub fn is_deafened(&self) -> bool {
+        self.deafened.load(Ordering::Relaxed)
+    }
+
+    /// In push-to-talk mode the microphone is only sent while `set_talking`
+    /// says the key is held.
+    pub fn set_push_to_talk(&self, enabled: bool) {
+        self.mic.push_to_talk.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_push_to_talk(&self) -> bool {
+        self.mic.push_to_talk.load(Ordering::Relaxed)
+    }
+
+    pub fn set_talking(&self, talking: bool) {
+        self.mic.talking.store(talking, Ordering::Relaxed);
+    }
+
+    pub fn is_talking(&self) -> bool {
+        self.mic.talking.load(Ordering::Relaxed)
+    }
+
+    /// Events about the calls and the audio pipeline, from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<AudioEvent> {
+        self.events.subscribe()
+    }
+
+    /// Starts a call over `connection`: an iroh `Connection`, or any other
+    /// `Transport`. `role` tells which side opens the control stream.
+    pub fn add_connection(&mut self, connection: impl Transport, role: Role) -> anyhow::Result<()> {
+        self.prune_connections();
+
+        let connection: Arc<dyn Transport> = Arc::new(connection);
+        let endpoint_id = connection.remote_id();
+        // a reconnecting endpoint replaces its previous call. The new call only
+        // starts once the old one has torn down, which would undo its setup
+        let previous = self.connect_pair.remove(&endpoint_id).map(|previous| {
+            let _ = previous.cancel.send(true);
+            (previous.connection, previous.reciver_thread)
+        });
         let conn_for_send = connection.clone();
         let conn_for_recv = connection.clone();
-
-        let (recv_data_prod, recv_data_cons) = tokio::sync::mpsc::channel(2);
-        let decoder_thread = build_decoder(recv_data_cons, self.decode_frame_prod.clone())?;
+        let decode_frame_prod = self.decode_frame_prod.clone();
+        let encoder_command_prod = self.encoder_command_prod.clone();
+        let ssrc = self.ssrc;
+        let events = self.events.clone();
         let mut send_data_cons = self.send_data_cons.resubscribe();
+        let (cancel, cancelled) = watch::channel(false);
+        let mut send_cancelled = cancelled.clone();
+        let hanging_up = Arc::new(AtomicBool::new(false));
+        let recv_hanging_up = hanging_up.clone();
 
         let sender_thread = tokio::task::spawn(async move {
-            while let Ok(frame) = send_data_cons.recv().await {
-                // TODO: encoding rtp frame
-                if conn_for_send.send_datagram(frame).is_err() {
-                    // TODO: cancellization
-                    return;
+            loop {
+                tokio::select! {
+                    frame = send_data_cons.recv() => {
+                        let frame = match frame {
+                            Ok(frame) => frame,
+                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::error::RecvError::Closed) => return,
+                        };
+                        if conn_for_send.send_datagram(frame).is_err() {
+                            return;
+                        }
+                    }
+                    _ = send_cancelled.changed() => return,
                 }
             }
         });
 
+        let (control_prod, control_outgoing) = mpsc::channel(16);
+        if self.is_mic_muted() {
+            // goes out right after the hello
+            let _ = control_prod.try_send(ControlMessage::Mute { muted: true });
+        }
+        let (control_incoming, control_cons) = mpsc::channel(16);
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities {
+                max_bitrate: self.bitrate.max,
+                // receivers follow whatever the peer sends
+                frame_durations: FrameDuration::ALL.to_vec(),
+                preferred_frame_duration: self.frame_duration,
+                dtx: true,
+                ..Default::default()
+            },
+        };
+        let control_thread = tokio::task::spawn(control(
+            connection.clone(),
+            role,
+            hello.clone(),
+            control_outgoing,
+            control_incoming,
+            cancelled.clone(),
+        ));
+        let control_channel = ControlChannel {
+            role,
+            hello,
+            outgoing: control_prod.clone(),
+            incoming: control_cons,
+        };
         let reciver_thread = tokio::task::spawn(async move {
-            // let ae1 = ae1.clone();
-            while let Ok(frame) = conn_for_recv.read_datagram().await {
-                // TODO: decoding rtp frame
-                // TODO: jitter
-                let _ = recv_data_prod
-                    .send(DecodeCommand::DecodeNormal(frame))
-                    .await;
+            if let Some((previous, previous_thread)) = previous {
+                let _ = previous_thread.await;
+                // nothing reads it any more, the peer may still be on it
+                previous.close(CLOSE_CODE_HANGUP, b"reconnected");
             }
+            let _ = events.send(AudioEvent::PeerConnected { endpoint_id });
+            receive(
+                conn_for_recv,
+                ssrc,
+                decode_frame_prod,
+                encoder_command_prod,
+                events,
+                control_channel,
+                cancelled,
+                recv_hanging_up,
+            )
+            .await
         });
 
         self.connect_pair.insert(
-            connection.remote_id(),
+            endpoint_id,
             ConnectPair {
                 connection,
                 sender_thread,
                 reciver_thread,
-                decoder_thread,
+                control_thread,
+                control: control_prod,
+                cancel,
+                hanging_up,
             },
         );
         Ok(())
     }
+
+    /// Hangs up the call with `endpoint_id` and waits until its tasks and
+    /// decoders have stopped. Its volume and mute are forgotten.
+    pub async fn remove_connection(&mut self, endpoint_id: EndpointId) -> anyhow::Result<()> {
+        let pair = self
+            .connect_pair
+            .remove(&endpoint_id)
+            .ok_or(Error::UnknownPeer(endpoint_id))?;
+        let closed = pair.close().await;
+        self.mixer_command(MixerCommand::ForgetEndpoint(endpoint_id))
+            .await;
+        closed
+    }
+
+    /// Plays what `endpoint_id` says at `volume`, 1.0 being unchanged, up to
+    /// `mixer::MAX_PEER_VOLUME`. Only we hear the difference. May be set
+    /// before the call starts, and is kept if the peer reconnects.
+    pub async fn set_peer_volume(
+        &self,
+        endpoint_id: EndpointId,
+        volume: f32,
+    ) -> anyhow::Result<()> {
+        if !volume.is_finite() {
+            return Err(Error::InvalidVolume(volume).into());
+        }
+        self.mixer_command(MixerCommand::SetVolume {
+            endpoint_id,
+            volume,
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Silences `endpoint_id` locally; its volume is kept for when it is
+    /// unmuted.
+    pub async fn mute_peer(&self, endpoint_id: EndpointId, muted: bool) -> anyhow::Result<()> {
+        self.mixer_command(MixerCommand::SetMuted { endpoint_id, muted })
+            .await;
+        Ok(())
+    }
+
+    async fn mixer_command(&self, command: MixerCommand) {
+        // the mixer only goes away on shutdown
+        let _ = self.decode_frame_prod.send(command).await;
+    }
+
+    /// Endpoints with a call in progress.
+    pub fn connections(&self) -> impl Iterator<Item = &EndpointId> {
+        self.connect_pair.keys()
+    }
+
+    /// Hangs up every call, then stops the encoder and the mixer and waits
+    /// for them. Every call is hung up even if some fail to.
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        for (endpoint_id, pair) in self.connect_pair.drain() {
+            if let Err(error) = pair.close().await {
+                errors.push(format!("{endpoint_id}: {error}"));
+            }
+        }
+
+        // with its channels closed, each thread returns on its next round
+        let threads = [self.encoder_thread.take(), self.mixer_thread.take()];
+        drop(self);
+        let joined = tokio::task::spawn_blocking(move || {
+            let mut joined = true;
+            for thread in threads.into_iter().flatten() {
+                thread.thread().unpark();
+                joined &= thread.join().is_ok();
+            }
+            joined
+        })
+        .await;
+        if !joined.unwrap_or(false) {
+            errors.push("the encoder or the mixer panicked".to_owned());
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(Error::Shutdown(errors).into()),
+        }
+    }
+
+    /// Forgets calls the remote side has already ended.
+    fn prune_connections(&mut self) {
+        self.connect_pair
+            .retain(|_, pair| !pair.reciver_thread.is_finished());
+    }
+}
+
+impl ConnectPair {
+    async fn close(mut self) -> anyhow::Result<()> {
+        let bye = ControlMessage::Bye {
+            reason: "hang up".to_owned(),
+        };
+        self.hanging_up.store(true, Ordering::Relaxed);
+        // the control task returns once the peer has read the bye
+        let mut control_ended = None;
+        if self.control.send(bye).await.is_ok() {
+            control_ended = tokio::time::timeout(BYE_TIMEOUT, &mut self.control_thread)
+                .await
+                .ok();
+        }
+        self.connection.close(CLOSE_CODE_HANGUP, b"hang up");
+        let _ = self.cancel.send(true);
+        self.sender_thread.await?;
+        self.reciver_thread.await?;
+        // a task handle must not be polled again once it has completed
+        match control_ended {
+            Some(ended) => ended?,
+            None => self.control_thread.await?,
+        }
+        Ok(())
+    }
+}
+
+/// One remote media stream: the connection it arrives on and its RTP SSRC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamId {
+    pub endpoint_id: EndpointId,
+    pub ssrc: u32,
+}
+
+impl RemoteStream {
+    fn build(
+        stream_id: StreamId,
+        frame_samples: usize,
+        decoder_output: mpsc::Sender<MixerCommand>,
+        events: broadcast::Sender<AudioEvent>,
+    ) -> anyhow::Result<Self> {
+        let (decoder_input, decoder_cons) = tokio::sync::mpsc::channel(2);
+        let decoder_thread = build_decoder(stream_id, decoder_cons, decoder_output, events)?;
+        Ok(RemoteStream {
+            jitter: JitterBuffer::new(frame_samples as u32),
+            frame: frame_length(frame_samples),
+            due: Duration::ZERO,
+            decoder_input,
+            decoder_thread,
+            last_arrival: Instant::now(),
+            stalled: false,
+        })
+    }
+
+    /// Follows the peer when it switches to another frame duration.
+    fn set_frame_samples(&mut self, frame_samples: usize) {
+        let frame = frame_length(frame_samples);
+        if frame != self.frame {
+            self.frame = frame;
+            self.jitter.set_frame_samples(frame_samples as u32);
+        }
+    }
+}
+
+fn frame_length(frame_samples: usize) -> Duration {
+    Duration::from_secs_f64(frame_samples as f64 / SAMPLE_RATE as f64)
+}
+
+/// Receiver task of a connection: sorts packets into per-SSRC streams and
+/// feeds each stream's decoder once per playout tick.
+///
+/// It also reports the loss of those streams back to the sender, signed with
+/// our own `ssrc`, and passes what the peer reports on to the encoder along
+/// with the transport's path statistics.
+///
+/// Messages from the control stream turn into events; a bye ends the call.
+///
+/// When the call ends, either side hanging up, the decoders are joined and
+/// the endpoint's mixer inputs freed before the task returns.
+#[allow(clippy::too_many_arguments)]
+async fn receive(
+    connection: Arc<dyn Transport>,
+    ssrc: u32,
+    decoder_output: mpsc::Sender<MixerCommand>,
+    encoder_commands: mpsc::Sender<EncoderCommand>,
+    events: broadcast::Sender<AudioEvent>,
+    mut control: ControlChannel,
+    mut cancelled: watch::Receiver<bool>,
+    hanging_up: Arc<AtomicBool>,
+) {
+    let endpoint_id = connection.remote_id();
+    let mut streams: HashMap<u32, RemoteStream> = HashMap::new();
+    let mut link = LinkMonitor::default();
+    let mut playout = tokio::time::interval(PLAYOUT_TICK);
+    let mut reports = tokio::time::interval(RECEIVER_REPORT_INTERVAL);
+    // peers without a control stream never say hello and are never timed out
+    let mut last_control: Option<Instant> = None;
+    let mut control_open = true;
+    if connection.protocol_version() < 2 {
+        // such a peer says no hello, it only decodes what version 1 fixed
+        let _ = encoder_commands
+            .send(EncoderCommand::Negotiated {
+                endpoint_id,
+                parameters: CallParameters::version1(),
+            })
+            .await;
+    }
+
+    let reason = loop {
+        tokio::select! {
+            datagram = connection.read_datagram() => {
+                let datagram = match datagram {
+                    Ok(datagram) => datagram,
+                    Err(reason) => break reason,
+                };
+                if is_rtcp(&datagram) {
+                    // every block on this link is about the stream we send on it
+                    let report = ReceiverReport::parse(&datagram).ok();
+                    if let Some(loss) = report.and_then(|report| report.worst_loss()) {
+                        let feedback = LinkFeedback {
+                            loss,
+                            path: connection.path_stats(),
+                        };
+                        let _ = encoder_commands.try_send(EncoderCommand::Feedback {
+                            endpoint_id,
+                            feedback,
+                        });
+                    }
+                    continue;
+                }
+                let Ok(packet) = RtpPacket::parse(datagram) else {
+                    continue;
+                };
+                // comfort noise says nothing about the frame duration
+                let frame_samples = match packet.header.payload_type {
+                    OPUS_PAYLOAD_TYPE => match opus_frame_samples(&packet.payload) {
+                        Some(frame_samples) => Some(frame_samples),
+                        None => continue,
+                    },
+                    COMFORT_NOISE_PAYLOAD_TYPE => None,
+                    _ => continue,
+                };
+                let stream_id = StreamId {
+                    endpoint_id,
+                    ssrc: packet.header.ssrc,
+                };
+                let stream = match streams.entry(stream_id.ssrc) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let stream = RemoteStream::build(
+                            stream_id,
+                            frame_samples.unwrap_or(FrameDuration::default().samples()),
+                            decoder_output.clone(),
+                            events.clone(),
+                        );
+                        match stream {
+                            Ok(stream) => entry.insert(stream),
+                            Err(_) => continue,
+                        }
+                    }
+                };
+                if let Some(frame_samples) = frame_samples {
+                    stream.set_frame_samples(frame_samples);
+                }
+                stream.last_arrival = Instant::now();
+                stream.jitter.push(packet, stream.last_arrival);
+            }
+            _ = playout.tick() => {
+                // dropping a stream closes its decoder input, which ends its decoder thread
+                let now = Instant::now();
+                streams.retain(|_, stream| now - stream.last_arrival < STREAM_TIMEOUT);
+                for stream in streams.values_mut() {
+                    stream.due += PLAYOUT_TICK;
+                    while stream.due >= stream.frame {
+                        stream.due -= stream.frame;
+                        let command = stream.jitter.pop();
+                        link.record(&command, stream.jitter.jitter());
+                        let full = matches!(
+                            stream.decoder_input.try_send(command),
+                            Err(mpsc::error::TrySendError::Full(_))
+                        );
+                        if full && !stream.stalled {
+                            let _ =
+                                events.send(AudioEvent::PipelineStalled(PipelineStage::Decoder));
+                        }
+                        stream.stalled = full;
+                    }
+                }
+                if let Some(stats) = link.tick(connection.rtt()) {
+                    let _ = events.send(AudioEvent::LinkQualityChanged {
+                        endpoint_id,
+                        quality: stats.quality(),
+                        stats,
+                    });
+                    let _ = control.outgoing.try_send(ControlMessage::Stats(stats));
+                }
+                if last_control.is_some_and(|last| now - last > CONTROL_TIMEOUT) {
+                    break DisconnectReason::TimedOut;
+                }
+            }
+            message = control.incoming.recv(), if control_open => {
+                let Some(message) = message else {
+                    control_open = false;
+                    continue;
+                };
+                last_control = Some(Instant::now());
+                match message {
+                    ControlMessage::Hello(hello) => {
+                        let parameters = match negotiate(control.role, &control.hello, &hello) {
+                            Ok(parameters) => parameters,
+                            Err(error) => {
+                                connection.close(CLOSE_CODE_INCOMPATIBLE, b"incompatible");
+                                break DisconnectReason::Incompatible(error.to_string());
+                            }
+                        };
+                        let _ = events.send(AudioEvent::CallNegotiated {
+                            endpoint_id,
+                            parameters,
+                        });
+                        let _ = encoder_commands
+                            .send(EncoderCommand::Negotiated {
+                                endpoint_id,
+                                parameters,
+                            })
+                            .await;
+                    }
+                    ControlMessage::Keepalive => {}
+                    ControlMessage::Mute { muted } => {
+                        let _ = events.send(AudioEvent::PeerMuted { endpoint_id, muted });
+                    }
+                    ControlMessage::Stats(stats) => {
+                        let _ = events.send(AudioEvent::RemoteLinkQualityChanged {
+                            endpoint_id,
+                            quality: stats.quality(),
+                            stats,
+                        });
+                    }
+                    ControlMessage::Bye { reason } => {
+                        // the peer waits for us to close once we have its bye
+                        connection.close(CLOSE_CODE_HANGUP, b"bye");
+                        break DisconnectReason::RemoteBye(reason);
+                    }
+                }
+            }
+            _ = reports.tick() => {
+                let blocks = streams.values_mut().filter_map(|stream| stream.jitter.report());
+                let report = ReceiverReport {
+                    ssrc,
+                    blocks: blocks.collect(),
+                };
+                if !report.blocks.is_empty() {
+                    // a failed send shows up on the next read
+                    let _ = connection.send_datagram(report.to_bytes());
+                }
+            }
+            _ = cancelled.changed() => break DisconnectReason::LocalHangup,
+        }
+    };
+    // however the link ended, we hung up first
+    let reason = if hanging_up.load(Ordering::Relaxed) {
+        DisconnectReason::LocalHangup
+    } else {
+        reason
+    };
+
+    // closing the decoder inputs ends the decoder threads
+    let decoder_threads: Vec<_> = streams
+        .into_values()
+        .map(|stream| stream.decoder_thread)
+        .collect();
+    let _ = tokio::task::spawn_blocking(move || {
+        for decoder_thread in decoder_threads {
+            let _ = decoder_thread.join();
+        }
+    })
+    .await;
+    let _ = decoder_output
+        .send(MixerCommand::RemoveEndpoint(endpoint_id))
+        .await;
+    // a peer that hung up on purpose is not reconnecting; a lost one may
+    if matches!(
+        reason,
+        DisconnectReason::RemoteHangup(_)
+            | DisconnectReason::RemoteBye(_)
+            | DisconnectReason::Incompatible(_)
+    ) {
+        let _ = decoder_output
+            .send(MixerCommand::ForgetEndpoint(endpoint_id))
+            .await;
+    }
+    let _ = encoder_commands
+        .send(EncoderCommand::RemoveEndpoint(endpoint_id))
+        .await;
+    let _ = events.send(AudioEvent::PeerDisconnected {
+        endpoint_id,
+        reason,
+    });
 }
 
 #[derive(Debug, Clone)]
 pub struct DecodedFrame {
+    pub stream_id: StreamId,
     pub frame: Vec<f32>,
 }
 
 pub fn build_encoder(
     encoder_input: rtrb::Consumer<f32>,
     encoder_output: tokio::sync::broadcast::Sender<Bytes>,
+    encoder_commands: tokio::sync::mpsc::Receiver<EncoderCommand>,
+    ssrc: u32,
+    bitrate: BitrateBounds,
+    frame_duration: FrameDuration,
+    mic: Arc<MicGate>,
 ) -> anyhow::Result<std::thread::JoinHandle<()>> {
     let encoder_process = std::thread::Builder::new()
         .name("Audio Encoder Thread".to_owned())
-        .spawn(|| {
-            if encode(encoder_input, encoder_output).is_err() {
+        .spawn(move || {
+            if encode(
+                encoder_input,
+                encoder_output,
+                encoder_commands,
+                ssrc,
+                bitrate,
+                frame_duration,
+                mic,
+            )
+            .is_err()
+            {
                 // cancellation
             }
         })?;
@@ -130,13 +889,15 @@ pub fn build_encoder(
 }
 
 pub fn build_decoder(
+    stream_id: StreamId,
     decoder_input: tokio::sync::mpsc::Receiver<DecodeCommand>,
-    decoder_output: tokio::sync::mpsc::Sender<DecodedFrame>,
+    decoder_output: tokio::sync::mpsc::Sender<MixerCommand>,
+    events: broadcast::Sender<AudioEvent>,
 ) -> anyhow::Result<std::thread::JoinHandle<()>> {
     let decode_process = std::thread::Builder::new()
-        .name("Audio Encoder Thread".to_owned())
-        .spawn(|| {
-            if decode(decoder_input, decoder_output).is_err() {
+        .name("Audio Decoder Thread".to_owned())
+        .spawn(move || {
+            if decode(stream_id, decoder_input, decoder_output, events).is_err() {
                 // cancellation
             }
         })?;
@@ -144,97 +905,288 @@ pub fn build_decoder(
 }
 
 pub fn build_mixer(
-    mixer_input: tokio::sync::mpsc::Receiver<DecodedFrame>,
+    mixer_input: tokio::sync::mpsc::Receiver<MixerCommand>,
     mixer_output: rtrb::Producer<f32>,
+    frame_duration: FrameDuration,
+    deafened: Arc<AtomicBool>,
+    events: broadcast::Sender<AudioEvent>,
 ) -> anyhow::Result<std::thread::JoinHandle<()>> {
     let decode_process = std::thread::Builder::new()
-        .name("Audio Encoder Thread".to_owned())
-        .spawn(|| {
-            if mix(mixer_input, mixer_output).is_err() {
+        .name("Audio Mixer Thread".to_owned())
+        .spawn(move || {
+            if mix(mixer_input, mixer_output, frame_duration, deafened, events).is_err() {
                 // cancellation
             }
         })?;
     Ok(decode_process)
 }
 
+/// `settings`: what the encoder starts with, before any receiver report.
+pub fn build_opus_encoder(settings: &EncoderSettings) -> anyhow::Result<opus::Encoder> {
+    let mut encoder = opus::Encoder::new(48000, opus::Channels::Mono, opus::Application::Voip)?;
+    encoder.set_vbr(true)?;
+    settings.apply(&mut encoder)?;
+    Ok(encoder)
+}
+
+/// What the encoder thread keeps about each peer it sends to.
+struct PeerLink {
+    congestion: CongestionController,
+    fec: bool,
+    frame_duration: FrameDuration,
+    dtx: bool,
+}
+
 pub fn encode(
     mut encoder_input: rtrb::Consumer<f32>,
     encoder_output: tokio::sync::broadcast::Sender<Bytes>,
+    mut encoder_commands: tokio::sync::mpsc::Receiver<EncoderCommand>,
+    ssrc: u32,
+    bitrate: BitrateBounds,
+    frame_duration: FrameDuration,
+    mic: Arc<MicGate>,
 ) -> anyhow::Result<()> {
-    let mut encoder = opus::Encoder::new(48000, opus::Channels::Mono, opus::Application::Voip)?;
-    encoder.set_bitrate(opus::Bitrate::Auto)?;
-    encoder.set_vbr(true)?;
-    encoder.set_inband_fec(true)?;
-    // encoder.set_packet_loss_perc(0)?;
-
+    // no loss until a receiver reports some
+    let mut settings = EncoderSettings::new(bitrate.clamp(START_BITRATE), 0.0);
+    let mut encoder = build_opus_encoder(&settings)?;
+    let mut sequencer = RtpSequencer::new(ssrc, OPUS_PAYLOAD_TYPE);
     let mut output = [0u8; 4096];
+    let mut frame = vec![0f32; FrameDuration::Ms60.samples()];
+    // the same stream goes to every peer, so it is tuned for the worst link
+    let mut peers: HashMap<EndpointId, PeerLink> = HashMap::new();
+    let new_peer = || PeerLink {
+        congestion: CongestionController::new(bitrate),
+        fec: true,
+        frame_duration: FrameDuration::default(),
+        dtx: false,
+    };
+    let mut dtx = false;
+    let comfort_noise_interval =
+        (COMFORT_NOISE_INTERVAL.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+    // samples since the last comfort noise packet, while in DTX
+    let mut paused: Option<usize> = None;
 
     loop {
-        while let Ok(encoder_input) = encoder_input.read_chunk(FRAME20MS) {
-            let encode_size = encoder.encode_float(encoder_input.as_slices().0, &mut output)?;
-            let _ = encoder_output.send(Bytes::copy_from_slice(&output[..encode_size]));
+        loop {
+            match encoder_commands.try_recv() {
+                Ok(EncoderCommand::Feedback {
+                    endpoint_id,
+                    feedback,
+                }) => {
+                    peers
+                        .entry(endpoint_id)
+                        .or_insert_with(new_peer)
+                        .congestion
+                        .on_feedback(feedback);
+                }
+                Ok(EncoderCommand::Negotiated {
+                    endpoint_id,
+                    parameters,
+                }) => {
+                    let peer = peers.entry(endpoint_id).or_insert_with(new_peer);
+                    peer.congestion.limit(parameters.max_bitrate);
+                    peer.fec = parameters.fec;
+                    peer.frame_duration = parameters.frame_duration;
+                    peer.dtx = parameters.dtx;
+                }
+                Ok(EncoderCommand::RemoveEndpoint(endpoint_id)) => {
+                    peers.remove(&endpoint_id);
+                }
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => return Ok(()),
+            }
         }
-        std::thread::park();
+        let worst_bitrate = peers.values().map(|peer| peer.congestion.bitrate()).min();
+        // FEC is only worth its bits for the peers that decode it
+        let worst_loss = peers
+            .values()
+            .filter(|peer| peer.fec)
+            .map(|peer| peer.congestion.loss())
+            .reduce(f32::max);
+        let wanted = EncoderSettings::new(
+            worst_bitrate.unwrap_or(bitrate.clamp(START_BITRATE)),
+            worst_loss.unwrap_or(0.0),
+        );
+        if wanted != settings {
+            wanted.apply(&mut encoder)?;
+            settings = wanted;
+        }
+        // a peer that does not expect gaps would conceal them as losses
+        let wanted_dtx = !peers.is_empty() && peers.values().all(|peer| peer.dtx);
+        if wanted_dtx != dtx {
+            encoder.set_dtx(wanted_dtx)?;
+            dtx = wanted_dtx;
+        }
+
+        // peers that agreed on different durations all decode the default one
+        let mut durations = peers.values().map(|peer| peer.frame_duration);
+        let frame_samples = match durations.next() {
+            None => frame_duration,
+            Some(first) if durations.all(|duration| duration == first) => first,
+            Some(_) => FrameDuration::default(),
+        }
+        .samples();
+
+        while let Ok(encoder_input) = encoder_input.read_chunk(frame_samples) {
+            let (first, second) = encoder_input.as_slices();
+            frame[..first.len()].copy_from_slice(first);
+            frame[first.len()..frame_samples].copy_from_slice(second);
+            encoder_input.commit_all();
+            if !mic.is_open() {
+                // keep the stream going, DTX makes the silence cheap
+                frame[..frame_samples].fill(0.0);
+            }
+            let encode_size = encoder.encode_float(&frame[..frame_samples], &mut output)?;
+
+            // no voice: send a comfort noise packet now and then instead of frames
+            let packet = if dtx && encoder.get_in_dtx()? {
+                match paused {
+                    Some(samples) if samples < comfort_noise_interval => {
+                        paused = Some(samples + frame_samples);
+                        sequencer.skip(frame_samples as u32);
+                        continue;
+                    }
+                    _ => {
+                        paused = Some(frame_samples);
+                        let level = noise_level(&frame[..frame_samples]);
+                        sequencer.comfort_noise(level, frame_samples as u32)
+                    }
+                }
+            } else {
+                if paused.take().is_some() {
+                    sequencer.start_talkspurt();
+                }
+                sequencer.packetize(
+                    Bytes::copy_from_slice(&output[..encode_size]),
+                    frame_samples as u32,
+                )
+            };
+            if encoder_output.send(packet.to_bytes()).is_err() {
+                // every receiver is gone: the services have shut down
+                return Ok(());
+            }
+        }
+        std::thread::park_timeout(PARK_TIMEOUT);
     }
 }
 
 pub fn decode(
+    stream_id: StreamId,
     decoder_input: tokio::sync::mpsc::Receiver<DecodeCommand>,
-    decoder_output: tokio::sync::mpsc::Sender<DecodedFrame>,
+    decoder_output: tokio::sync::mpsc::Sender<MixerCommand>,
+    events: broadcast::Sender<AudioEvent>,
 ) -> anyhow::Result<()> {
     let mut decoder = opus::Decoder::new(48000, opus::Channels::Mono)?;
+    let mut stalled = false;
     let mut decoder_input = decoder_input;
 
-    let mut frame = [0f32; FRAME20MS];
+    let mut frame = vec![0f32; FrameDuration::Ms60.samples()];
+    // FEC and PLC rebuild a frame as long as the packets of the stream
+    let mut frame_samples = FrameDuration::default().samples();
+    let mut comfort_noise = ComfortNoise::default();
 
     let decoder_output = decoder_output;
 
-    loop {
-        let decode_size = match decoder_input.blocking_recv() {
-            Some(DecodeCommand::DecodeNormal(packet)) => {
-                decoder.decode_float(&packet, &mut frame, false)
+    while let Some(command) = decoder_input.blocking_recv() {
+        let decode_size = match command {
+            DecodeCommand::DecodeNormal(_) => {
+                frame_samples = decode_command(&mut decoder, &command, &mut frame)?;
+                frame_samples
             }
-            Some(DecodeCommand::DecodeFEC(packet)) => {
-                decoder.decode_float(&packet, &mut frame, true)
-            }
-            Some(DecodeCommand::DecodePLC) => decoder.decode_float(&[], &mut frame, false),
-            None => {
-                return Ok(());
+            DecodeCommand::ComfortNoise(level) => {
+                comfort_noise.fill(level, &mut frame[..frame_samples]);
+                frame_samples
             }
-        }?;
-        if let Err(mpsc::error::TrySendError::Closed(_)) = decoder_output.try_send(DecodedFrame {
+            _ => decode_command(&mut decoder, &command, &mut frame[..frame_samples])?,
+        };
+        match decoder_output.try_send(MixerCommand::Frame(DecodedFrame {
+            stream_id,
             frame: frame[..decode_size].to_vec(),
-        }) {
-            // TODO: cancel
-            return Ok(());
+        })) {
+            Ok(()) => stalled = false,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if !stalled {
+                    let _ = events.send(AudioEvent::PipelineStalled(PipelineStage::Mixer));
+                }
+                stalled = true;
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+pub fn decode_command(
+    decoder: &mut opus::Decoder,
+    command: &DecodeCommand,
+    frame: &mut [f32],
+) -> Result<usize, opus::Error> {
+    match command {
+        DecodeCommand::DecodeNormal(packet) => decoder.decode_float(packet, frame, false),
+        DecodeCommand::DecodeFEC(packet) => decoder.decode_float(packet, frame, true),
+        DecodeCommand::DecodePLC => decoder.decode_float(&[], frame, false),
+        // generating noise is up to the caller, which keeps the generator
+        DecodeCommand::ComfortNoise(_) | DecodeCommand::Silence => {
+            frame.fill(0.0);
+            Ok(frame.len())
         }
     }
 }
 
 pub fn mix(
-    mixer_input: tokio::sync::mpsc::Receiver<DecodedFrame>,
+    mixer_input: tokio::sync::mpsc::Receiver<MixerCommand>,
     mixer_output: rtrb::Producer<f32>,
+    frame_duration: FrameDuration,
+    deafened: Arc<AtomicBool>,
+    events: broadcast::Sender<AudioEvent>,
 ) -> anyhow::Result<()> {
     let mut mixer_input = mixer_input;
     let mut mixer_output = mixer_output;
 
+    let frame_samples = frame_duration.samples();
+    let mut mixer = Mixer::new(frame_samples);
+    let mut frame = vec![0f32; frame_samples];
+
     loop {
-        if let Ok(mut mixer_output) = mixer_output.write_chunk(FRAME20MS) {
+        loop {
             match mixer_input.try_recv() {
-                Ok(frame) => {
-                    mixer_output.as_mut_slices().0.copy_from_slice(&frame.frame);
-                }
-                Err(mpsc::error::TryRecvError::Empty) => {
-                    mixer_output
-                        .as_mut_slices()
-                        .0
-                        .copy_from_slice(&[0f32; FRAME20MS]);
-                }
-                Err(mpsc::error::TryRecvError::Disconnected) => {
-                    return Ok(());
+                Ok(MixerCommand::Frame(decoded)) => mixer.push(decoded.stream_id, &decoded.frame),
+                Ok(MixerCommand::RemoveEndpoint(endpoint_id)) => mixer.remove_endpoint(endpoint_id),
+                Ok(MixerCommand::ForgetEndpoint(endpoint_id)) => mixer.forget_endpoint(endpoint_id),
+                Ok(MixerCommand::SetVolume {
+                    endpoint_id,
+                    volume,
+                }) => mixer.set_volume(endpoint_id, volume),
+                Ok(MixerCommand::SetMuted { endpoint_id, muted }) => {
+                    mixer.set_muted(endpoint_id, muted)
                 }
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => return Ok(()),
             }
         }
-        std::thread::park();
+        if let Ok(mut mixer_output) = mixer_output.write_chunk(frame_samples) {
+            mixer.mix_into(&mut frame);
+            if deafened.load(Ordering::Relaxed) {
+                // still mixed, so that talkers are still detected
+                frame.fill(0.0);
+            }
+            for (stream_id, speaking) in mixer.speaking_changes() {
+                let _ = events.send(AudioEvent::PeerSpeaking {
+                    stream_id,
+                    speaking,
+                });
+            }
+            let (first, second) = mixer_output.as_mut_slices();
+            first.copy_from_slice(&frame[..first.len()]);
+            second.copy_from_slice(&frame[first.len()..]);
+            mixer_output.commit_all();
+        }
+        std::thread::park_timeout(PARK_TIMEOUT);
     }
 }
+
+/// Samples in the Opus packet `payload`, `None` if it is not one.
+fn opus_frame_samples(payload: &[u8]) -> Option<usize> {
+    let samples = opus::packet::get_nb_samples(payload, SAMPLE_RATE).ok()?;
+    (samples > 0 && samples <= FrameDuration::Ms60.samples()).then_some(samples)
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use crate::{DecodeCommand, rtp::RTP_CLOCK_RATE};
+
+/// Playout ticks over which link statistics are gathered, one second of
+/// 10 ms ticks.
+const REPORT_TICKS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkQuality {
+    Good,
+    Degraded,
+    Poor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkStats {
+    /// Share of frames that had to be concealed with FEC or PLC.
+    pub loss: f32,
+    /// Largest interarrival jitter among the streams of the connection.
+    pub jitter: Duration,
+    pub rtt: Duration,
+}
+
+impl LinkStats {
+    pub fn quality(&self) -> LinkQuality {
+        if self.loss < 0.02
+            && self.jitter < Duration::from_millis(30)
+            && self.rtt < Duration::from_millis(300)
+        {
+            LinkQuality::Good
+        } else if self.loss < 0.1
+            && self.jitter < Duration::from_millis(60)
+            && self.rtt < Duration::from_millis(600)
+        {
+            LinkQuality::Degraded
+        } else {
+            LinkQuality::Poor
+        }
+    }
+}
+
+/// Gathers the playout outcome of every stream of a connection and reports
+/// when its quality changes.
+#[derive(Debug, Default)]
+pub struct LinkMonitor {
+    ticks: usize,
+    frames: usize,
+    concealed: usize,
+    jitter: f32,
+    quality: Option<LinkQuality>,
+}
+
+impl LinkMonitor {
+    /// Records one decode command handed out by a stream whose jitter buffer
+    /// measures `jitter` media clock ticks.
+    pub fn record(&mut self, command: &DecodeCommand, jitter: f32) {
+        match command {
+            // a pause the sender chose says nothing about the link
+            DecodeCommand::ComfortNoise(_) | DecodeCommand::Silence => return,
+            DecodeCommand::DecodeNormal(_) => {}
+            _ => self.concealed += 1,
+        }
+        self.frames += 1;
+        self.jitter = self.jitter.max(jitter);
+    }
+
+    /// Called once per playout tick. Returns the statistics of the last
+    /// period when the link quality differs from the one last reported.
+    pub fn tick(&mut self, rtt: Duration) -> Option<LinkStats> {
+        self.ticks += 1;
+        if self.ticks < REPORT_TICKS {
+            return None;
+        }
+
+        let stats = (self.frames > 0).then(|| LinkStats {
+            loss: self.concealed as f32 / self.frames as f32,
+            jitter: Duration::from_secs_f32(self.jitter / RTP_CLOCK_RATE as f32),
+            rtt,
+        });
+        *self = LinkMonitor {
+            quality: self.quality,
+            ..Default::default()
+        };
+
+        // nothing played out, e.g. the peer is silent: keep the last verdict
+        let stats = stats?;
+        let quality = stats.quality();
+        if self.quality == Some(quality) {
+            return None;
+        }
+        self.quality = Some(quality);
+        Some(stats)
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::{HashMap, VecDeque};
+
+use iroh::EndpointId;
+
+use crate::StreamId;
+
+/// -3 dB applied to the sum before clipping.
+pub const MIX_HEADROOM: f32 = 0.707_945_8;
+/// Level above which `soft_clip` starts to bend the signal.
+pub const SOFT_CLIP_KNEE: f32 = 0.8;
+/// Frames an input may queue before its oldest one is dropped.
+const MAX_QUEUED_FRAMES: usize = 4;
+/// Late frames concealed by fading out the last frame; silence after that.
+const CONCEALED_FRAMES: usize = 1;
+/// Late frames after which an input is considered gone and freed.
+const MAX_IDLE_FRAMES: usize = 250;
+/// Loudest a single peer may be turned up to, +12 dB.
+pub const MAX_PEER_VOLUME: f32 = 4.0;
+/// RMS level, about -40 dBFS, above which a frame counts as speech.
+const SPEECH_LEVEL: f32 = 0.01;
+/// Quiet frames after which a talker counts as silent again.
+const SPEECH_HANGOVER: usize = 15;
+
+struct MixerInput {
+    queue: VecDeque<f32>,
+    last_frame: Vec<f32>,
+    /// Gain the previous frame ended at; the next one ramps from there.
+    gain: f32,
+    late_frames: usize,
+    speaking: bool,
+    quiet_frames: usize,
+}
+
+/// Local volume of everything one peer sends.
+#[derive(Debug, Clone, Copy)]
+struct PeerGain {
+    volume: f32,
+    muted: bool,
+}
+
+impl Default for PeerGain {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+        }
+    }
+}
+
+impl PeerGain {
+    fn gain(&self) -> f32 {
+        if self.muted { 0.0 } else { self.volume }
+    }
+}
+
+/// Sums one frame per remote stream each tick.
+pub struct Mixer {
+    frame_size: usize,
+    inputs: HashMap<StreamId, MixerInput>,
+    /// Kept across reconnects, so that a peer comes back at the same volume,
+    /// and may be set before its call starts. Forgotten once it has left.
+    gains: HashMap<EndpointId, PeerGain>,
+    sum: Vec<f32>,
+    speaking_changes: Vec<(StreamId, bool)>,
+}
+
+impl Mixer {
+    pub fn new(frame_size: usize) -> Self {
+        Self {
+            frame_size,
+            inputs: HashMap::new(),
+            gains: HashMap::new(),
+            sum: vec![0.0; frame_size],
+            speaking_changes: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, stream_id: StreamId, samples: &[f32]) {
+        let frame_size = self.frame_size;
+        let gain = self.gain(stream_id.endpoint_id);
+        let input = self.inputs.entry(stream_id).or_insert_with(|| MixerInput {
+            queue: VecDeque::with_capacity(frame_size * MAX_QUEUED_FRAMES),
+            last_frame: vec![0.0; frame_size],
+            gain,
+            late_frames: 0,
+            speaking: false,
+            quiet_frames: SPEECH_HANGOVER,
+        });
+        input.queue.extend(samples);
+        // peers may send frames longer than ours
+        let limit = frame_size.max(samples.len()) * MAX_QUEUED_FRAMES;
+        while input.queue.len() > limit {
+            input.queue.drain(..frame_size);
+        }
+    }
+
+    /// Plays the streams of `endpoint_id` at `volume`, 1.0 being unchanged.
+    /// A volume that is not a number is ignored, it would spoil the whole mix.
+    pub fn set_volume(&mut self, endpoint_id: EndpointId, volume: f32) {
+        if !volume.is_finite() {
+            return;
+        }
+        self.gains.entry(endpoint_id).or_default().volume = volume.clamp(0.0, MAX_PEER_VOLUME);
+    }
+
+    /// Silences the streams of `endpoint_id` without forgetting their volume.
+    pub fn set_muted(&mut self, endpoint_id: EndpointId, muted: bool) {
+        self.gains.entry(endpoint_id).or_default().muted = muted;
+    }
+
+    fn gain(&self, endpoint_id: EndpointId) -> f32 {
+        self.gains.get(&endpoint_id).map_or(1.0, PeerGain::gain)
+    }
+
+    /// Drops the volume and mute of `endpoint_id`, which is not coming back.
+    pub fn forget_endpoint(&mut self, endpoint_id: EndpointId) {
+        self.gains.remove(&endpoint_id);
+    }
+
+    /// Frees the inputs of every stream received from `endpoint_id`. Those
+    /// still talking are reported silent.
+    pub fn remove_endpoint(&mut self, endpoint_id: EndpointId) {
+        let speaking_changes = &mut self.speaking_changes;
+        self.inputs.retain(|&stream_id, input| {
+            if stream_id.endpoint_id != endpoint_id {
+                return true;
+            }
+            if input.speaking {
+                speaking_changes.push((stream_id, false));
+            }
+            false
+        });
+    }
+
+    /// Mixes the next frame of every input into `output`, which must hold
+    /// exactly one frame.
+    pub fn mix_into(&mut self, output: &mut [f32]) {
+        let frame_size = self.frame_size;
+        self.sum.fill(0.0);
+
+        for (&stream_id, input) in self.inputs.iter_mut() {
+            let gain = self
+                .gains
+                .get(&stream_id.endpoint_id)
+                .map_or(1.0, PeerGain::gain);
+            // ramped over the frame, a jump in gain would click
+            let from = input.gain;
+            let step = (gain - from) / frame_size as f32;
+            input.gain = gain;
+
+            if input.queue.len() >= frame_size {
+                for (last, sample) in input
+                    .last_frame
+                    .iter_mut()
+                    .zip(input.queue.drain(..frame_size))
+                {
+                    *last = sample;
+                }
+                for (i, (sum, &sample)) in
+                    self.sum.iter_mut().zip(input.last_frame.iter()).enumerate()
+                {
+                    *sum += sample * (from + i as f32 * step);
+                }
+                input.late_frames = 0;
+                if input.detect_speech(rms(&input.last_frame)) {
+                    self.speaking_changes.push((stream_id, input.speaking));
+                }
+                continue;
+            }
+
+            // late talker: fade the previous frame out instead of cutting it
+            input.late_frames += 1;
+            if input.late_frames <= CONCEALED_FRAMES {
+                let fade = 1.0 / frame_size as f32;
+                for (i, (sum, &sample)) in
+                    self.sum.iter_mut().zip(input.last_frame.iter()).enumerate()
+                {
+                    *sum += sample * (1.0 - i as f32 * fade) * (from + i as f32 * step);
+                }
+            }
+            if input.detect_speech(0.0) {
+                self.speaking_changes.push((stream_id, input.speaking));
+            }
+        }
+        self.inputs
+            .retain(|_, input| input.late_frames < MAX_IDLE_FRAMES);
+
+        for (out, &sum) in output.iter_mut().zip(self.sum.iter()) {
+            *out = soft_clip(sum * MIX_HEADROOM);
+        }
+    }
+
+    /// Streams that started or stopped talking since the last call.
+    pub fn speaking_changes(&mut self) -> std::vec::Drain<'_, (StreamId, bool)> {
+        self.speaking_changes.drain(..)
+    }
+}
+
+impl MixerInput {
+    /// Updates the talking state with the level of the frame just mixed.
+    /// Returns `true` when it changed.
+    fn detect_speech(&mut self, level: f32) -> bool {
+        if level >= SPEECH_LEVEL {
+            self.quiet_frames = 0;
+        } else {
+            self.quiet_frames += 1;
+        }
+        let speaking = self.quiet_frames < SPEECH_HANGOVER;
+        let changed = speaking != self.speaking;
+        self.speaking = speaking;
+        changed
+    }
+}
+
+fn rms(frame: &[f32]) -> f32 {
+    (frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32).sqrt()
+}
+
+/// Writes everyone but one participant into `output`, given the sum of all
+/// participants and that participant's own frame.
+pub fn mix_minus(total: &[f32], own: &[f32], output: &mut [f32]) {
+    for ((out, &total), &own) in output.iter_mut().zip(total.iter()).zip(own.iter()) {
+        *out = soft_clip((total - own) * MIX_HEADROOM);
+    }
+}
+
+/// Linear below `SOFT_CLIP_KNEE`, then bends smoothly towards ±1.0.
+pub fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= SOFT_CLIP_KNEE {
+        return sample;
+    }
+    let range = 1.0 - SOFT_CLIP_KNEE;
+    let bent = SOFT_CLIP_KNEE + range * ((magnitude - SOFT_CLIP_KNEE) / range).tanh();
+    bent.copysign(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+
+    const FRAME: usize = 4;
+
+    fn stream(endpoint: u8, ssrc: u32) -> StreamId {
+        StreamId {
+            endpoint_id: SecretKey::from_bytes(&[endpoint; 32]).public(),
+            ssrc,
+        }
+    }
+
+    fn mix(mixer: &mut Mixer) -> [f32; FRAME] {
+        let mut output = [0.0; FRAME];
+        mixer.mix_into(&mut output);
+        output
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-6,
+                "{actual:?} != {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn mixes_two_streams() {
+        let mut mixer = Mixer::new(FRAME);
+        mixer.push(stream(1, 1), &[0.1, 0.2, -0.1, 0.0]);
+        mixer.push(stream(2, 1), &[0.2, -0.2, -0.1, 0.3]);
+
+        let expected = [0.3, 0.0, -0.2, 0.3].map(|sum| sum * MIX_HEADROOM);
+        assert_close(&mix(&mut mixer), &expected);
+    }
+
+    #[test]
+    fn mix_minus_leaves_out_the_listener() {
+        let own = [0.4, -0.2, 0.1, 0.0];
+        let others = [0.1, 0.1, -0.3, 0.2];
+        let total: Vec<f32> = own.iter().zip(&others).map(|(a, b)| a + b).collect();
+
+        let mut output = [0.0; FRAME];
+        mix_minus(&total, &own, &mut output);
+        assert_close(&output, &others.map(|x| x * MIX_HEADROOM));
+    }
+
+    #[test]
+    fn soft_clip_bends_full_scale_below_one() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-SOFT_CLIP_KNEE), -SOFT_CLIP_KNEE);
+
+        let full = soft_clip(1.0);
+        assert!(full > SOFT_CLIP_KNEE && full < 1.0);
+        assert_eq!(soft_clip(-1.0), -full);
+        assert!(soft_clip(4.0) > full && soft_clip(4.0) <= 1.0);
+
+        // two peers at full scale still fit
+        let mut mixer = Mixer::new(FRAME);
+        mixer.push(stream(1, 1), &[1.0; FRAME]);
+        mixer.push(stream(2, 1), &[1.0; FRAME]);
+        assert!(mix(&mut mixer).iter().all(|&x| x > full && x <= 1.0));
+    }
+
+    #[test]
+    fn streams_of_one_peer_queue_apart() {
+        let mut mixer = Mixer::new(FRAME);
+        mixer.push(stream(1, 1), &[0.1; FRAME]);
+        mixer.push(stream(1, 2), &[0.2; FRAME]);
+
+        // both play together rather than one after the other
+        assert_close(&mix(&mut mixer), &[0.3 * MIX_HEADROOM; FRAME]);
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest_frame() {
+        let mut mixer = Mixer::new(FRAME);
+        for frame in 0..MAX_QUEUED_FRAMES + 2 {
+            mixer.push(stream(1, 1), &[frame as f32 * 0.1; FRAME]);
+        }
+        assert_close(&mix(&mut mixer), &[0.2 * MIX_HEADROOM; FRAME]);
+    }
+
+    #[test]
+    fn late_frame_fades_the_last_one_out() {
+        let mut mixer = Mixer::new(FRAME);
+        mixer.push(stream(1, 1), &[0.5; FRAME]);
+        mix(&mut mixer);
+
+        let faded: Vec<f32> = (0..FRAME)
+            .map(|i| 0.5 * (1.0 - i as f32 / FRAME as f32) * MIX_HEADROOM)
+            .collect();
+        assert_close(&mix(&mut mixer), &faded);
+        assert_close(&mix(&mut mixer), &[0.0; FRAME]);
+    }
+
+    #[test]
+    fn volume_ramps_to_its_target() {
+        let mut mixer = Mixer::new(FRAME);
+        let id = stream(1, 1);
+        mixer.push(id, &[0.4; FRAME]);
+        mix(&mut mixer);
+
+        mixer.set_volume(id.endpoint_id, 0.5);
+        mixer.push(id, &[0.4; FRAME]);
+        let ramp = mix(&mut mixer);
+        assert_close(&ramp[..1], &[0.4 * MIX_HEADROOM]);
+        assert!(ramp.windows(2).all(|pair| pair[1] < pair[0]));
+        assert!(ramp[FRAME - 1] > 0.2 * MIX_HEADROOM);
+
+        mixer.push(id, &[0.4; FRAME]);
+        assert_close(&mix(&mut mixer), &[0.2 * MIX_HEADROOM; FRAME]);
+    }
+
+    #[test]
+    fn volume_that_is_not_finite_is_ignored() {
+        let mut mixer = Mixer::new(FRAME);
+        let id = stream(1, 1);
+        mixer.set_volume(id.endpoint_id, 0.5);
+        mixer.set_volume(id.endpoint_id, f32::NAN);
+        mixer.set_volume(id.endpoint_id, f32::INFINITY);
+        mixer.push(id, &[0.4; FRAME]);
+        assert_close(&mix(&mut mixer), &[0.2 * MIX_HEADROOM; FRAME]);
+
+        mixer.set_volume(id.endpoint_id, 100.0);
+        assert_eq!(mixer.gain(id.endpoint_id), MAX_PEER_VOLUME);
+        mixer.set_muted(id.endpoint_id, true);
+        assert_eq!(mixer.gain(id.endpoint_id), 0.0);
+    }
+
+    #[test]
+    fn reports_talkers_starting_and_stopping() {
+        let mut mixer = Mixer::new(FRAME);
+        let id = stream(1, 1);
+        mixer.push(id, &[0.3; FRAME]);
+        mix(&mut mixer);
+        assert_eq!(mixer.speaking_changes().collect::<Vec<_>>(), [(id, true)]);
+
+        for _ in 1..SPEECH_HANGOVER {
+            mixer.push(id, &[0.0; FRAME]);
+            mix(&mut mixer);
+        }
+        assert_eq!(mixer.speaking_changes().count(), 0);
+
+        mixer.push(id, &[0.0; FRAME]);
+        mix(&mut mixer);
+        assert_eq!(mixer.speaking_changes().collect::<Vec<_>>(), [(id, false)]);
+    }
+
+    #[test]
+    fn removed_talker_is_reported_silent() {
+        let mut mixer = Mixer::new(FRAME);
+        let talking = stream(1, 1);
+        let quiet = stream(2, 1);
+        mixer.push(talking, &[0.3; FRAME]);
+        mixer.push(quiet, &[0.0; FRAME]);
+        mix(&mut mixer);
+        mixer.speaking_changes().for_each(drop);
+
+        mixer.remove_endpoint(talking.endpoint_id);
+        mixer.remove_endpoint(quiet.endpoint_id);
+        assert_eq!(
+            mixer.speaking_changes().collect::<Vec<_>>(),
+            [(talking, false)]
+        );
+    }
+}
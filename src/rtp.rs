@@ -0,0 +1,444 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::error::Error;
+
+pub const RTP_VERSION: u8 = 2;
+pub const RTP_HEADER_SIZE: usize = 12;
+/// Opus uses a 48 kHz media clock whatever the actual sample rate (RFC 7587).
+pub const RTP_CLOCK_RATE: u32 = 48000;
+/// Dynamic payload type announced for Opus.
+pub const OPUS_PAYLOAD_TYPE: u8 = 111;
+/// Static payload type of comfort noise (RFC 3389). The sender pauses on
+/// purpose after such a packet; its payload is the noise level in -dBov.
+pub const COMFORT_NOISE_PAYLOAD_TYPE: u8 = 13;
+/// RTCP packet type of a receiver report (RFC 3550 6.4.2).
+pub const RTCP_RECEIVER_REPORT: u8 = 201;
+pub const RTCP_HEADER_SIZE: usize = 8;
+pub const REPORT_BLOCK_SIZE: usize = 24;
+/// A report block count has 5 bits.
+pub const MAX_REPORT_BLOCKS: usize = 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpHeader {
+    /// Set on the first packet of a talkspurt.
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    /// Capture time of the first sample, in `RTP_CLOCK_RATE` units.
+    pub timestamp: u32,
+    /// Stream id of the sender.
+    pub ssrc: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct RtpPacket {
+    pub header: RtpHeader,
+    pub payload: Bytes,
+}
+
+impl RtpPacket {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(RTP_HEADER_SIZE + self.payload.len());
+        buf.put_u8(RTP_VERSION << 6);
+        buf.put_u8(((self.header.marker as u8) << 7) | (self.header.payload_type & 0x7f));
+        buf.put_u16(self.header.sequence);
+        buf.put_u32(self.header.timestamp);
+        buf.put_u32(self.header.ssrc);
+        buf.put_slice(&self.payload);
+        buf.freeze()
+    }
+
+    /// Parses a datagram into a packet.
+    /// CSRC lists, header extensions and padding are skipped; the payload is
+    /// a zero-copy slice of `datagram`.
+    pub fn parse(datagram: Bytes) -> Result<Self, Error> {
+        if datagram.len() < RTP_HEADER_SIZE {
+            return Err(Error::RtpPacketTooShort);
+        }
+
+        let version = datagram[0] >> 6;
+        if version != RTP_VERSION {
+            return Err(Error::UnsupportedRtpVersion(version));
+        }
+        let has_padding = datagram[0] & 0x20 != 0;
+        let has_extension = datagram[0] & 0x10 != 0;
+        let csrc_count = (datagram[0] & 0x0f) as usize;
+
+        let header = RtpHeader {
+            marker: datagram[1] & 0x80 != 0,
+            payload_type: datagram[1] & 0x7f,
+            sequence: u16::from_be_bytes([datagram[2], datagram[3]]),
+            timestamp: u32::from_be_bytes([datagram[4], datagram[5], datagram[6], datagram[7]]),
+            ssrc: u32::from_be_bytes([datagram[8], datagram[9], datagram[10], datagram[11]]),
+        };
+
+        let mut offset = RTP_HEADER_SIZE + csrc_count * 4;
+        if has_extension {
+            if datagram.len() < offset + 4 {
+                return Err(Error::RtpPacketTooShort);
+            }
+            let extension_words =
+                u16::from_be_bytes([datagram[offset + 2], datagram[offset + 3]]) as usize;
+            offset += 4 + extension_words * 4;
+        }
+        if datagram.len() < offset {
+            return Err(Error::RtpPacketTooShort);
+        }
+
+        let mut end = datagram.len();
+        if has_padding {
+            let padding = datagram[end - 1] as usize;
+            if padding == 0 || padding > end - offset {
+                return Err(Error::InvalidRtpPadding);
+            }
+            end -= padding;
+        }
+        if offset == end {
+            return Err(Error::EmptyRtpPayload);
+        }
+
+        Ok(RtpPacket {
+            header,
+            payload: datagram.slice(offset..end),
+        })
+    }
+}
+
+/// Tells RTCP from RTP sharing the same link by the second byte, which
+/// holds the RTCP packet type (RFC 5761 4).
+pub fn is_rtcp(datagram: &[u8]) -> bool {
+    datagram.len() >= 2 && (192..=223).contains(&datagram[1])
+}
+
+/// Reception statistics of one source, as a receiver report carries them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportBlock {
+    /// Stream the statistics are about.
+    pub ssrc: u32,
+    /// Share of packets lost since the previous report, in 1/256.
+    pub fraction_lost: u8,
+    /// Packets lost since reception started; duplicates may make it negative.
+    pub cumulative_lost: i32,
+    /// Highest sequence number received, extended with the count of wraps.
+    pub highest_sequence: u32,
+    /// Interarrival jitter in `RTP_CLOCK_RATE` units.
+    pub jitter: u32,
+}
+
+impl ReportBlock {
+    /// `fraction_lost` between 0.0 and 1.0.
+    pub fn loss(&self) -> f32 {
+        self.fraction_lost as f32 / 256.0
+    }
+}
+
+/// RTCP receiver report: what a receiver tells the senders it hears from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiverReport {
+    /// Stream id of the reporter.
+    pub ssrc: u32,
+    /// At most `MAX_REPORT_BLOCKS`, the others are left out.
+    pub blocks: Vec<ReportBlock>,
+}
+
+impl ReceiverReport {
+    /// Highest loss among the blocks.
+    pub fn worst_loss(&self) -> Option<f32> {
+        self.blocks.iter().map(ReportBlock::loss).reduce(f32::max)
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let blocks = &self.blocks[..self.blocks.len().min(MAX_REPORT_BLOCKS)];
+        let size = RTCP_HEADER_SIZE + blocks.len() * REPORT_BLOCK_SIZE;
+        let mut buf = BytesMut::with_capacity(size);
+        buf.put_u8((RTP_VERSION << 6) | blocks.len() as u8);
+        buf.put_u8(RTCP_RECEIVER_REPORT);
+        // length in 32-bit words minus one
+        buf.put_u16((size / 4 - 1) as u16);
+        buf.put_u32(self.ssrc);
+        for block in blocks {
+            let cumulative_lost = block.cumulative_lost.clamp(-(1 << 23), (1 << 23) - 1);
+            buf.put_u32(block.ssrc);
+            buf.put_u8(block.fraction_lost);
+            buf.put_slice(&cumulative_lost.to_be_bytes()[1..]);
+            buf.put_u32(block.highest_sequence);
+            buf.put_u32(block.jitter);
+            // no sender reports are sent, so there is no round trip to time
+            buf.put_u32(0);
+            buf.put_u32(0);
+        }
+        buf.freeze()
+    }
+
+    /// Parses a datagram holding a receiver report. Packets of a compound
+    /// RTCP packet after the first one are ignored.
+    pub fn parse(datagram: &[u8]) -> Result<Self, Error> {
+        if datagram.len() < RTCP_HEADER_SIZE {
+            return Err(Error::RtcpPacketTooShort);
+        }
+
+        let version = datagram[0] >> 6;
+        if version != RTP_VERSION {
+            return Err(Error::UnsupportedRtpVersion(version));
+        }
+        if datagram[1] != RTCP_RECEIVER_REPORT {
+            return Err(Error::UnsupportedRtcpPacketType(datagram[1]));
+        }
+        let count = (datagram[0] & 0x1f) as usize;
+        let length = (u16::from_be_bytes([datagram[2], datagram[3]]) as usize + 1) * 4;
+        if datagram.len() < length || length < RTCP_HEADER_SIZE + count * REPORT_BLOCK_SIZE {
+            return Err(Error::RtcpPacketTooShort);
+        }
+
+        let word = |offset: usize| {
+            u32::from_be_bytes([
+                datagram[offset],
+                datagram[offset + 1],
+                datagram[offset + 2],
+                datagram[offset + 3],
+            ])
+        };
+        let blocks = (0..count)
+            .map(|index| {
+                let offset = RTCP_HEADER_SIZE + index * REPORT_BLOCK_SIZE;
+                ReportBlock {
+                    ssrc: word(offset),
+                    fraction_lost: datagram[offset + 4],
+                    // sign-extend the 24-bit count
+                    cumulative_lost: (word(offset + 4) << 8) as i32 >> 8,
+                    highest_sequence: word(offset + 8),
+                    jitter: word(offset + 12),
+                }
+            })
+            .collect();
+
+        Ok(ReceiverReport {
+            ssrc: word(4),
+            blocks,
+        })
+    }
+}
+
+/// Copies `datagram`, which must be a valid RTP packet, with its SSRC replaced.
+/// Everything else, payload included, is left as it is.
+pub fn retag_ssrc(datagram: &[u8], ssrc: u32) -> Bytes {
+    let mut tagged = BytesMut::from(datagram);
+    tagged[8..RTP_HEADER_SIZE].copy_from_slice(&ssrc.to_be_bytes());
+    tagged.freeze()
+}
+
+/// Stamps the frames of one outgoing stream with sequence numbers and media
+/// timestamps. Both start at random values as RFC 3550 recommends.
+#[derive(Debug, Clone)]
+pub struct RtpSequencer {
+    ssrc: u32,
+    payload_type: u8,
+    sequence: u16,
+    timestamp: u32,
+    marker: bool,
+}
+
+impl RtpSequencer {
+    pub fn new(ssrc: u32, payload_type: u8) -> Self {
+        Self {
+            ssrc,
+            payload_type,
+            sequence: rand::random(),
+            timestamp: rand::random(),
+            marker: true,
+        }
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Sets the marker bit on the next packet.
+    pub fn start_talkspurt(&mut self) {
+        self.marker = true;
+    }
+
+    /// Wraps `payload`, which covers `samples` samples of media, into the next
+    /// packet of the stream.
+    pub fn packetize(&mut self, payload: Bytes, samples: u32) -> RtpPacket {
+        self.next_packet(self.payload_type, payload, samples)
+    }
+
+    /// Announces a pause at noise `level`, in place of a frame of `samples`.
+    pub fn comfort_noise(&mut self, level: u8, samples: u32) -> RtpPacket {
+        self.next_packet(
+            COMFORT_NOISE_PAYLOAD_TYPE,
+            Bytes::from(vec![level]),
+            samples,
+        )
+    }
+
+    /// Lets the media clock run over a frame that is not sent.
+    pub fn skip(&mut self, samples: u32) {
+        self.timestamp = self.timestamp.wrapping_add(samples);
+    }
+
+    fn next_packet(&mut self, payload_type: u8, payload: Bytes, samples: u32) -> RtpPacket {
+        let packet = RtpPacket {
+            header: RtpHeader {
+                marker: self.marker,
+                payload_type,
+                sequence: self.sequence,
+                timestamp: self.timestamp,
+                ssrc: self.ssrc,
+            },
+            payload,
+        };
+        self.marker = false;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(samples);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> RtpPacket {
+        RtpPacket {
+            header: RtpHeader {
+                marker: true,
+                payload_type: OPUS_PAYLOAD_TYPE,
+                sequence: 0xfffe,
+                timestamp: 0x1234_5678,
+                ssrc: 0xdead_beef,
+            },
+            payload: Bytes::from_static(b"opus"),
+        }
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let bytes = packet().to_bytes();
+        assert_eq!(bytes.len(), RTP_HEADER_SIZE + 4);
+        assert_eq!(bytes[0] >> 6, RTP_VERSION);
+
+        let parsed = RtpPacket::parse(bytes).unwrap();
+        assert_eq!(parsed.header, packet().header);
+        assert_eq!(&parsed.payload[..], b"opus");
+    }
+
+    #[test]
+    fn parse_skips_csrcs_extension_and_padding() {
+        let mut datagram = BytesMut::new();
+        // padding, extension, two CSRCs
+        datagram.put_u8((RTP_VERSION << 6) | 0x20 | 0x10 | 2);
+        datagram.put_u8(OPUS_PAYLOAD_TYPE);
+        datagram.put_u16(7);
+        datagram.put_u32(960);
+        datagram.put_u32(42);
+        datagram.put_u32(1);
+        datagram.put_u32(2);
+        // extension of one word
+        datagram.put_u16(0xbede);
+        datagram.put_u16(1);
+        datagram.put_u32(0);
+        datagram.put_slice(b"opus");
+        datagram.put_slice(&[0, 0, 3]);
+
+        let parsed = RtpPacket::parse(datagram.freeze()).unwrap();
+        assert_eq!(parsed.header.sequence, 7);
+        assert_eq!(parsed.header.ssrc, 42);
+        assert_eq!(&parsed.payload[..], b"opus");
+    }
+
+    #[test]
+    fn parse_rejects_malformed() {
+        let bytes = packet().to_bytes();
+        assert!(matches!(
+            RtpPacket::parse(bytes.slice(..RTP_HEADER_SIZE - 1)),
+            Err(Error::RtpPacketTooShort)
+        ));
+        assert!(matches!(
+            RtpPacket::parse(bytes.slice(..RTP_HEADER_SIZE)),
+            Err(Error::EmptyRtpPayload)
+        ));
+
+        let mut version1 = BytesMut::from(&bytes[..]);
+        version1[0] = 1 << 6;
+        assert!(matches!(
+            RtpPacket::parse(version1.freeze()),
+            Err(Error::UnsupportedRtpVersion(1))
+        ));
+
+        let mut padded = BytesMut::from(&bytes[..]);
+        padded[0] |= 0x20;
+        *padded.last_mut().unwrap() = 200;
+        assert!(matches!(
+            RtpPacket::parse(padded.freeze()),
+            Err(Error::InvalidRtpPadding)
+        ));
+    }
+
+    #[test]
+    fn tells_rtcp_from_rtp() {
+        let report = ReceiverReport {
+            ssrc: 1,
+            blocks: Vec::new(),
+        };
+        assert!(is_rtcp(&report.to_bytes()));
+        // marked, so the second byte is 0x80 | 111
+        assert!(!is_rtcp(&packet().to_bytes()));
+        assert!(!is_rtcp(&[RTP_VERSION << 6]));
+    }
+
+    #[test]
+    fn receiver_report_round_trip() {
+        let report = ReceiverReport {
+            ssrc: 0x0102_0304,
+            blocks: vec![
+                ReportBlock {
+                    ssrc: 7,
+                    fraction_lost: 64,
+                    cumulative_lost: -3,
+                    highest_sequence: 0x0001_fffe,
+                    jitter: 480,
+                },
+                ReportBlock {
+                    ssrc: 8,
+                    fraction_lost: 0,
+                    cumulative_lost: 1000,
+                    highest_sequence: 12,
+                    jitter: 0,
+                },
+            ],
+        };
+        let bytes = report.to_bytes();
+        assert_eq!(bytes.len(), RTCP_HEADER_SIZE + 2 * REPORT_BLOCK_SIZE);
+
+        let parsed = ReceiverReport::parse(&bytes).unwrap();
+        assert_eq!(parsed, report);
+        assert_eq!(parsed.worst_loss(), Some(0.25));
+    }
+
+    #[test]
+    fn receiver_report_clamps_cumulative_loss() {
+        let report = ReceiverReport {
+            ssrc: 1,
+            blocks: vec![ReportBlock {
+                ssrc: 2,
+                fraction_lost: 0,
+                cumulative_lost: i32::MAX,
+                highest_sequence: 0,
+                jitter: 0,
+            }],
+        };
+        let parsed = ReceiverReport::parse(&report.to_bytes()).unwrap();
+        assert_eq!(parsed.blocks[0].cumulative_lost, (1 << 23) - 1);
+    }
+
+    #[test]
+    fn retag_keeps_everything_but_the_ssrc() {
+        let tagged = retag_ssrc(&packet().to_bytes(), 99);
+        let parsed = RtpPacket::parse(tagged).unwrap();
+        assert_eq!(parsed.header.ssrc, 99);
+        assert_eq!(parsed.header.sequence, packet().header.sequence);
+        assert_eq!(&parsed.payload[..], b"opus");
+    }
+}
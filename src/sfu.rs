@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use iroh::{EndpointId, endpoint::Connection};
+use tokio::sync::RwLock;
+
+use crate::rtp::{ReceiverReport, RtpPacket, is_rtcp, retag_ssrc};
+
+/// Selective forwarding unit: relays every participant's packets, payload
+/// untouched, to all the other participants and leaves mixing to them.
+///
+/// Each participant gets a stream id which replaces the SSRC of the packets it
+/// sends, so receivers can tell the relayed streams apart. Receiver reports
+/// go back to the participant whose stream they are about.
+pub struct ForwardingUnit {
+    participants: Arc<RwLock<HashMap<EndpointId, Forwarded>>>,
+    next_stream_id: AtomicU32,
+}
+
+struct Forwarded {
+    connection: Connection,
+    stream_id: u32,
+}
+
+impl Default for ForwardingUnit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ForwardingUnit {
+    pub fn new() -> Self {
+        Self {
+            participants: Arc::default(),
+            next_stream_id: AtomicU32::new(1),
+        }
+    }
+
+    pub async fn add_connection(&self, connection: Connection) {
+        let endpoint_id = connection.remote_id();
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let participants = self.participants.clone();
+
+        participants.write().await.insert(
+            endpoint_id,
+            Forwarded {
+                connection: connection.clone(),
+                stream_id,
+            },
+        );
+
+        tokio::task::spawn(async move {
+            while let Ok(datagram) = connection.read_datagram().await {
+                if is_rtcp(&datagram) {
+                    if let Ok(report) = ReceiverReport::parse(&datagram) {
+                        forward_report(stream_id, &report, &*participants.read().await);
+                    }
+                    continue;
+                }
+                if RtpPacket::parse(datagram.clone()).is_err() {
+                    continue;
+                }
+                let tagged = retag_ssrc(&datagram, stream_id);
+                for (id, receiver) in participants.read().await.iter() {
+                    if *id != endpoint_id {
+                        let _ = receiver.connection.send_datagram(tagged.clone());
+                    }
+                }
+            }
+
+            let mut participants = participants.write().await;
+            // a reconnection may already have replaced this entry
+            if participants
+                .get(&endpoint_id)
+                .is_some_and(|current| current.connection.stable_id() == connection.stable_id())
+            {
+                participants.remove(&endpoint_id);
+            }
+        });
+    }
+}
+
+/// Splits a report among the senders of the streams it is about, signed with
+/// the stream id of the reporter.
+fn forward_report(
+    reporter: u32,
+    report: &ReceiverReport,
+    participants: &HashMap<EndpointId, Forwarded>,
+) {
+    for sender in participants.values() {
+        let blocks: Vec<_> = report
+            .blocks
+            .iter()
+            .filter(|block| block.ssrc == sender.stream_id)
+            .copied()
+            .collect();
+        if blocks.is_empty() {
+            continue;
+        }
+        let report = ReceiverReport {
+            ssrc: reporter,
+            blocks,
+        };
+        let _ = sender.connection.send_datagram(report.to_bytes());
+    }
+}
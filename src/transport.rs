@@ -0,0 +1,340 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use iroh::{
+    EndpointId, SecretKey,
+    endpoint::{Connection, SendDatagramError, VarInt},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    sync::{Mutex, mpsc, watch},
+};
+
+use crate::{PROTOCOL_VERSION, alpn_version, event::DisconnectReason};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Which side of the call we are: the caller opens the control stream, the
+/// callee accepts it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Caller,
+    Callee,
+}
+
+/// Reliable, ordered byte stream to the peer, next to the datagrams.
+pub struct ControlStream {
+    pub send: Box<dyn AsyncWrite + Send + Unpin>,
+    pub recv: Box<dyn AsyncRead + Send + Unpin>,
+}
+
+/// What the transport knows about the path to the peer. Counters only grow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathStats {
+    pub rtt: Duration,
+    /// Times the transport's own congestion control has backed off.
+    pub congestion_events: u64,
+    pub lost_packets: u64,
+    pub sent_packets: u64,
+}
+
+/// Unreliable datagram link to one peer, as `AudioServices` uses it.
+///
+/// Errors tell why the link is no longer usable; once one is returned every
+/// later call fails the same way.
+pub trait Transport: Send + Sync + 'static {
+    fn remote_id(&self) -> EndpointId;
+    /// Queues a datagram without waiting; it may be lost on the way.
+    fn send_datagram(&self, datagram: Bytes) -> Result<(), DisconnectReason>;
+    fn read_datagram(&self) -> BoxFuture<'_, Result<Bytes, DisconnectReason>>;
+    /// Opens the control stream as `role`. Only waits for the peer when
+    /// accepting; meant to be called once.
+    fn control_stream(&self, role: Role) -> BoxFuture<'_, Result<ControlStream, DisconnectReason>>;
+    /// Closes the link; the peer sees `RemoteHangup(code)`.
+    fn close(&self, code: u32, reason: &[u8]);
+    /// Current round-trip time estimate.
+    fn rtt(&self) -> Duration;
+    /// Protocol version agreed with the peer.
+    fn protocol_version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+    /// Path statistics, for congestion control. Transports that keep none
+    /// only know the round-trip time.
+    fn path_stats(&self) -> PathStats {
+        PathStats {
+            rtt: self.rtt(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Transport for Connection {
+    fn remote_id(&self) -> EndpointId {
+        Connection::remote_id(self)
+    }
+
+    fn send_datagram(&self, datagram: Bytes) -> Result<(), DisconnectReason> {
+        Connection::send_datagram(self, datagram).map_err(|error| match error {
+            SendDatagramError::ConnectionLost(error) => error.into(),
+            error => DisconnectReason::Lost(error.to_string()),
+        })
+    }
+
+    fn read_datagram(&self) -> BoxFuture<'_, Result<Bytes, DisconnectReason>> {
+        Box::pin(async move { Ok(Connection::read_datagram(self).await?) })
+    }
+
+    fn control_stream(&self, role: Role) -> BoxFuture<'_, Result<ControlStream, DisconnectReason>> {
+        Box::pin(async move {
+            let (send, recv) = match role {
+                Role::Caller => self.open_bi().await?,
+                Role::Callee => self.accept_bi().await?,
+            };
+            Ok(ControlStream {
+                send: Box::new(send),
+                recv: Box::new(recv),
+            })
+        })
+    }
+
+    fn close(&self, code: u32, reason: &[u8]) {
+        Connection::close(self, VarInt::from_u32(code), reason)
+    }
+
+    fn rtt(&self) -> Duration {
+        Connection::rtt(self)
+    }
+
+    fn protocol_version(&self) -> u8 {
+        alpn_version(Connection::alpn(self)).unwrap_or(1)
+    }
+
+    fn path_stats(&self) -> PathStats {
+        let path = Connection::stats(self).path;
+        PathStats {
+            rtt: path.rtt,
+            congestion_events: path.congestion_events,
+            lost_packets: path.lost_packets,
+            sent_packets: path.sent_packets,
+        }
+    }
+}
+
+/// Datagrams that may wait in a loopback link before new ones are dropped.
+const LOOPBACK_CAPACITY: usize = 64;
+/// Bytes the loopback control stream buffers in each direction.
+const LOOPBACK_CONTROL_CAPACITY: usize = 64 * 1024;
+
+/// One end of an in-process link, to run calls without networking.
+pub struct LoopbackTransport {
+    side: usize,
+    remote_id: EndpointId,
+    outgoing: mpsc::Sender<Bytes>,
+    incoming: Mutex<mpsc::Receiver<Bytes>>,
+    control: Mutex<Option<DuplexStream>>,
+    /// Which side closed the link, and with which code.
+    closed: Arc<watch::Sender<Option<(usize, u32)>>>,
+}
+
+impl LoopbackTransport {
+    /// Two linked ends with random endpoint ids. Each end reports the other
+    /// one's id as `remote_id`.
+    pub fn pair() -> (Self, Self) {
+        let a = SecretKey::from_bytes(&rand::random()).public();
+        let b = SecretKey::from_bytes(&rand::random()).public();
+        Self::pair_with_ids(a, b)
+    }
+
+    /// Two linked ends, the first one known as `a` and the second one as `b`.
+    pub fn pair_with_ids(a: EndpointId, b: EndpointId) -> (Self, Self) {
+        let (a_prod, b_cons) = mpsc::channel(LOOPBACK_CAPACITY);
+        let (b_prod, a_cons) = mpsc::channel(LOOPBACK_CAPACITY);
+        let closed = Arc::new(watch::Sender::new(None));
+        let (a_control, b_control) = tokio::io::duplex(LOOPBACK_CONTROL_CAPACITY);
+        (
+            LoopbackTransport {
+                side: 0,
+                remote_id: b,
+                outgoing: a_prod,
+                incoming: Mutex::new(a_cons),
+                control: Mutex::new(Some(a_control)),
+                closed: closed.clone(),
+            },
+            LoopbackTransport {
+                side: 1,
+                remote_id: a,
+                outgoing: b_prod,
+                incoming: Mutex::new(b_cons),
+                control: Mutex::new(Some(b_control)),
+                closed,
+            },
+        )
+    }
+
+    fn close_reason(&self) -> Option<DisconnectReason> {
+        let (side, code) = (*self.closed.borrow())?;
+        Some(if side == self.side {
+            DisconnectReason::LocalHangup
+        } else {
+            DisconnectReason::RemoteHangup(code as u64)
+        })
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn remote_id(&self) -> EndpointId {
+        self.remote_id
+    }
+
+    fn send_datagram(&self, datagram: Bytes) -> Result<(), DisconnectReason> {
+        if let Some(reason) = self.close_reason() {
+            return Err(reason);
+        }
+        match self.outgoing.try_send(datagram) {
+            // like a congested network, a full link drops the datagram
+            Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => Ok(()),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(peer_dropped()),
+        }
+    }
+
+    fn read_datagram(&self) -> BoxFuture<'_, Result<Bytes, DisconnectReason>> {
+        Box::pin(async move {
+            if let Some(reason) = self.close_reason() {
+                return Err(reason);
+            }
+            let mut closed = self.closed.subscribe();
+            let mut incoming = self.incoming.lock().await;
+            tokio::select! {
+                // a peer that closed the link and went away reports the close
+                datagram = incoming.recv() => {
+                    datagram.ok_or_else(|| self.close_reason().unwrap_or_else(peer_dropped))
+                }
+                _ = closed.wait_for(Option::is_some) => {
+                    Err(self.close_reason().unwrap_or(DisconnectReason::LocalHangup))
+                }
+            }
+        })
+    }
+
+    fn control_stream(
+        &self,
+        _role: Role,
+    ) -> BoxFuture<'_, Result<ControlStream, DisconnectReason>> {
+        Box::pin(async move {
+            if let Some(reason) = self.close_reason() {
+                return Err(reason);
+            }
+            // both ends exist from the start, so neither has to wait for the other
+            let control = self.control.lock().await.take();
+            let (recv, send) = tokio::io::split(control.ok_or_else(control_taken)?);
+            Ok(ControlStream {
+                send: Box::new(send),
+                recv: Box::new(recv),
+            })
+        })
+    }
+
+    fn close(&self, code: u32, _reason: &[u8]) {
+        self.closed.send_if_modified(|closed| {
+            if closed.is_some() {
+                return false;
+            }
+            *closed = Some((self.side, code));
+            true
+        });
+    }
+
+    fn rtt(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+fn peer_dropped() -> DisconnectReason {
+    DisconnectReason::Lost("loopback peer dropped".to_owned())
+}
+
+fn control_taken() -> DisconnectReason {
+    DisconnectReason::Lost("loopback control stream already open".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn loopback_carries_datagrams_both_ways() {
+        let (a, b) = LoopbackTransport::pair();
+        assert_ne!(a.remote_id(), b.remote_id());
+
+        a.send_datagram(Bytes::from_static(b"ping")).unwrap();
+        b.send_datagram(Bytes::from_static(b"pong")).unwrap();
+        assert_eq!(&b.read_datagram().await.unwrap()[..], b"ping");
+        assert_eq!(&a.read_datagram().await.unwrap()[..], b"pong");
+    }
+
+    #[tokio::test]
+    async fn full_loopback_drops_instead_of_failing() {
+        let (a, b) = LoopbackTransport::pair();
+        for _ in 0..LOOPBACK_CAPACITY + 1 {
+            a.send_datagram(Bytes::from_static(b"x")).unwrap();
+        }
+        for _ in 0..LOOPBACK_CAPACITY {
+            b.read_datagram().await.unwrap();
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), b.read_datagram())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn close_is_a_local_hangup_on_one_side_and_remote_on_the_other() {
+        let (a, b) = LoopbackTransport::pair();
+        // a read waiting when the link closes ends too
+        let pending = tokio::spawn(async move {
+            let reason = b.read_datagram().await.unwrap_err();
+            (b, reason)
+        });
+        tokio::task::yield_now().await;
+        a.close(7, b"bye");
+        a.close(8, b"again");
+
+        let (b, reason) = pending.await.unwrap();
+        assert_eq!(reason, DisconnectReason::RemoteHangup(7));
+        assert_eq!(
+            b.send_datagram(Bytes::new()),
+            Err(DisconnectReason::RemoteHangup(7))
+        );
+        assert_eq!(
+            a.read_datagram().await.unwrap_err(),
+            DisconnectReason::LocalHangup
+        );
+    }
+
+    #[tokio::test]
+    async fn control_stream_opens_once() {
+        let (a, b) = LoopbackTransport::pair();
+        let mut caller = a.control_stream(Role::Caller).await.unwrap();
+        let mut callee = b.control_stream(Role::Callee).await.unwrap();
+
+        caller.send.write_all(b"hello").await.unwrap();
+        let mut received = [0; 5];
+        callee.recv.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+
+        assert!(a.control_stream(Role::Caller).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dropped_peer_is_lost() {
+        let (a, b) = LoopbackTransport::pair();
+        drop(b);
+        assert!(matches!(
+            a.read_datagram().await,
+            Err(DisconnectReason::Lost(_))
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+//! Two `AudioServices` in one process, calling each other over a
+//! `LoopbackTransport` with file engines in place of sound cards.
+#![cfg(feature = "hachimi")]
+
+use std::time::Duration;
+
+use hachimi_cat::{
+    AudioServices, AudioServicesConfig,
+    event::{AudioEvent, DisconnectReason},
+    transport::{LoopbackTransport, Role, Transport},
+};
+use hacore::{
+    AudioPipeline,
+    file_audio_engine::{FileEngineConfig, FileSource},
+};
+use iroh::EndpointId;
+use tokio::{sync::broadcast, time::timeout};
+
+const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn service(source: FileSource) -> AudioServices {
+    AudioServices::with_config(AudioServicesConfig {
+        file_engine: Some(FileEngineConfig {
+            source,
+            sink: None,
+            realtime: true,
+        }),
+        // pure Rust, so it behaves the same wherever the test runs
+        pipeline: AudioPipeline::Hachimi(Default::default()),
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+/// Waits for the first event `matches` accepts.
+async fn wait_for(
+    events: &mut broadcast::Receiver<AudioEvent>,
+    mut matches: impl FnMut(&AudioEvent) -> bool,
+) -> AudioEvent {
+    timeout(EVENT_TIMEOUT, async {
+        loop {
+            match events.recv().await {
+                Ok(event) if matches(&event) => return event,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => panic!("events closed"),
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for an event")
+}
+
+fn disconnected(event: &AudioEvent, from: EndpointId) -> Option<DisconnectReason> {
+    match event {
+        AudioEvent::PeerDisconnected {
+            endpoint_id,
+            reason,
+        } if *endpoint_id == from => Some(reason.clone()),
+        _ => None,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn frames_reach_the_peer_and_hangups_are_reported() {
+    let (caller_end, callee_end) = LoopbackTransport::pair();
+    // each end knows the other one's id
+    let callee_id = caller_end.remote_id();
+    let caller_id = callee_end.remote_id();
+
+    let mut caller = service(FileSource::Sine {
+        frequency: 440.0,
+        amplitude: 0.5,
+    });
+    let mut callee = service(FileSource::Silence);
+    let mut caller_events = caller.subscribe();
+    let mut callee_events = callee.subscribe();
+
+    caller.add_connection(caller_end, Role::Caller).unwrap();
+    callee.add_connection(callee_end, Role::Callee).unwrap();
+
+    // the mixer reports the caller's stream once it has mixed speech from it
+    wait_for(&mut callee_events, |event| {
+        matches!(event, AudioEvent::PeerSpeaking { stream_id, speaking: true }
+            if stream_id.endpoint_id == caller_id)
+    })
+    .await;
+
+    caller.remove_connection(callee_id).await.unwrap();
+    let event = wait_for(&mut caller_events, |event| {
+        disconnected(event, callee_id).is_some()
+    })
+    .await;
+    assert_eq!(
+        disconnected(&event, callee_id),
+        Some(DisconnectReason::LocalHangup)
+    );
+    let event = wait_for(&mut callee_events, |event| {
+        disconnected(event, caller_id).is_some()
+    })
+    .await;
+    assert!(matches!(
+        disconnected(&event, caller_id),
+        Some(DisconnectReason::RemoteHangup(_) | DisconnectReason::RemoteBye(_))
+    ));
+
+    caller.shutdown().await.unwrap();
+    callee.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_reconnect_closes_the_replaced_connection() {
+    let local_id = iroh::SecretKey::from_bytes(&[1; 32]).public();
+    let peer_id = iroh::SecretKey::from_bytes(&[2; 32]).public();
+    let (first, first_peer) = LoopbackTransport::pair_with_ids(local_id, peer_id);
+    let (second, _second_peer) = LoopbackTransport::pair_with_ids(local_id, peer_id);
+
+    let mut service = service(FileSource::Silence);
+    service.add_connection(first, Role::Callee).unwrap();
+    service.add_connection(second, Role::Callee).unwrap();
+
+    let closed = timeout(EVENT_TIMEOUT, async {
+        loop {
+            if let Err(reason) = first_peer.read_datagram().await {
+                return reason;
+            }
+        }
+    })
+    .await
+    .expect("the replaced connection stayed open");
+    assert_eq!(
+        closed,
+        DisconnectReason::RemoteHangup(hachimi_cat::CLOSE_CODE_HANGUP as u64)
+    );
+
+    service.shutdown().await.unwrap();
+}